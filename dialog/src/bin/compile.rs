@@ -0,0 +1,25 @@
+//! Compiles a dialog project into binary form that is loaded without parsing:
+//! `cargo run -p dialog --bin compile -- path/to/root.drs path/to/root.drsc`
+
+use std::process::ExitCode;
+
+use dialog::exec::load_project;
+
+fn main() -> ExitCode {
+    let (Some(root), Some(output)) = (std::env::args().nth(1), std::env::args().nth(2)) else {
+        eprintln!("usage: compile <root.drs> <output.drsc>");
+        return ExitCode::FAILURE;
+    };
+    let script = match load_project(&root) {
+        Ok(script) => script,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = std::fs::write(&output, script.to_bytes()) {
+        eprintln!("error: can't write {output}: {err}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
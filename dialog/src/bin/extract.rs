@@ -0,0 +1,23 @@
+//! Writes every line and option of a dialog project as gettext PO template:
+//! `cargo run -p dialog --bin extract -- path/to/root.drs > texts.pot`
+
+use std::process::ExitCode;
+
+use dialog::exec::{extract_po, load_project};
+
+fn main() -> ExitCode {
+    let Some(root) = std::env::args().nth(1) else {
+        eprintln!("usage: extract <root.drs>");
+        return ExitCode::FAILURE;
+    };
+    match load_project(&root) {
+        Ok(script) => {
+            print!("{}", extract_po(&script));
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use std::{num::NonZeroU32, rc::Rc};
+
+use crate::{
+    error::{DialogError, ErrorKind},
+    grammar::{Character, Identifier, Text},
+    utils::fnv1a,
+};
+
+/// magic | version | checksum of the rest
+const HEADER_SIZE: usize = 4 + 2 + 4;
+
+/// Kind of binary file, each has its own header and version.
+pub(crate) struct Format {
+    /// First bytes of every file
+    magic: &'static [u8; 4],
+    /// Changes every time the layout does, older files are rejected
+    version: u16,
+    /// What the file is, for errors
+    name: &'static str,
+    /// What to do with the file of another version
+    outdated: &'static str,
+    error: fn(String) -> ErrorKind,
+}
+
+/// Script made by [`crate::interpreter::DirectScript::to_bytes`].
+pub(crate) const SCRIPT: Format = Format {
+    magic: b"DRSC",
+    version: 1,
+    name: "compiled dialog script",
+    outdated: "the script has to be compiled again",
+    error: ErrorKind::BadCompiledScript,
+};
+
+/// Execution state made by [`crate::interpreter::DirectExecution::snapshot`].
+pub(crate) const SNAPSHOT: Format = Format {
+    magic: b"DRSS",
+    version: 1,
+    name: "dialog snapshot",
+    outdated: "it was saved by another version of the game",
+    error: ErrorKind::BadSnapshot,
+};
+
+/// Value that can be written into compiled script.
+pub(crate) trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+/// Value that can be read back from compiled script.
+pub(crate) trait Decode: Sized {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String>;
+}
+
+/// Bytes of compiled script that are left to read.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < count {
+            return Err("unexpected end of data".into());
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("Exactly N bytes are taken"))
+    }
+
+    /// Reads tag of enum variant.
+    pub(crate) fn tag(&mut self) -> Result<u8, String> {
+        u8::decode(self)
+    }
+}
+
+impl Format {
+    /// Puts header in front of `payload`, the checksum covers all of it.
+    pub(crate) fn wrap(&self, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(self.magic);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&fnv1a(payload).to_le_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// Checks header of the file and reads the payload after it with `read`.
+    /// Payload has to be read till the end.
+    pub(crate) fn unwrap<T>(
+        &self,
+        bytes: &[u8],
+        read: impl FnOnce(&mut Reader<'_>) -> Result<T, String>,
+    ) -> Result<T, DialogError> {
+        let error = |message: String| DialogError::new((self.error)(message), None);
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(self.magic) {
+            return Err(error(format!("not a {}", self.name)));
+        }
+        let mut input = Reader {
+            bytes: &bytes[self.magic.len()..],
+        };
+        let version = u16::from_le_bytes(input.take_array().map_err(error)?);
+        if version != self.version {
+            return Err(error(format!(
+                "format version {version} isn't supported, expected {}, {}",
+                self.version, self.outdated
+            )));
+        }
+        let checksum = u32::from_le_bytes(input.take_array().map_err(error)?);
+        if fnv1a(input.bytes) != checksum {
+            return Err(error(
+                "checksum doesn't match, the file is corrupted".into(),
+            ));
+        }
+        let value = read(&mut input).map_err(error)?;
+        if !input.bytes.is_empty() {
+            return Err(error(format!(
+                "{} unexpected bytes after the end",
+                input.bytes.len()
+            )));
+        }
+        Ok(value)
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(input.take(1)?[0])
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        match input.tag()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(format!("invalid boolean {other}")),
+        }
+    }
+}
+
+/// Numbers are little-endian on every platform.
+macro_rules! impl_number {
+    ($($type:ty),*) => {$(
+        impl Encode for $type {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        impl Decode for $type {
+            fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+                Ok(Self::from_le_bytes(input.take_array()?))
+            }
+        }
+    )*};
+}
+
+impl_number!(u16, u32, u64, i32, f32, f64);
+
+/// Sizes and offsets are stored as `u32`, scripts never get that big.
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        u32::try_from(*self)
+            .expect("Script is too big to be compiled")
+            .encode(out);
+    }
+}
+
+impl Decode for usize {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(u32::decode(input)? as usize)
+    }
+}
+
+impl Encode for NonZeroU32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.get().encode(out);
+    }
+}
+
+impl Decode for NonZeroU32 {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        NonZeroU32::new(u32::decode(input)?).ok_or_else(|| "unexpected zero".into())
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for Rc<str> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        let len = usize::decode(input)?;
+        let bytes = input.take(len)?;
+        let text = std::str::from_utf8(bytes).map_err(|err| format!("invalid string: {err}"))?;
+        Ok(text.into())
+    }
+}
+
+impl Encode for Identifier {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out);
+    }
+}
+
+impl Decode for Identifier {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(Self::from(&*Rc::<str>::decode(input)?))
+    }
+}
+
+impl Encode for Text {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out);
+    }
+}
+
+impl Decode for Text {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(Self::from(&*Rc::<str>::decode(input)?))
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        match input.tag()? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            other => Err(format!("invalid option tag {other}")),
+        }
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode(out);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        let len = usize::decode(input)?;
+        // Every item takes at least a byte, so bad length can't reserve too much
+        let mut items = Vec::with_capacity(len.min(input.bytes.len()));
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Decode> Decode for Box<[T]> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(Vec::decode(input)?.into_boxed_slice())
+    }
+}
+
+impl<T: Decode> Decode for Rc<[T]> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(Vec::decode(input)?.into())
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Box<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Rc<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+impl<A: Encode, B: Encode, C: Encode> Encode for (A, B, C) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+        self.2.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode, C: Decode> Decode for (A, B, C) {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok((A::decode(input)?, B::decode(input)?, C::decode(input)?))
+    }
+}
+
+impl Encode for Character {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.name.encode(out);
+        self.color.encode(out);
+        self.portrait.encode(out);
+        self.blip.encode(out);
+    }
+}
+
+impl Decode for Character {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(Self {
+            name: Text::decode(input)?,
+            color: Option::decode(input)?,
+            portrait: Option::decode(input)?,
+            blip: Option::decode(input)?,
+        })
+    }
+}
+
+impl Decode for Rc<Character> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(Rc::new(Character::decode(input)?))
+    }
+}
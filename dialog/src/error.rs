@@ -0,0 +1,255 @@
+use std::{fmt::Display, path::Path, rc::Rc};
+
+use pest::error::{InputLocation, LineColLocation};
+
+use crate::grammar::{Identifier, MAX_EXPRESSION_DEPTH, Rule};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// Source doesn't match grammar at all.
+    Syntax(String),
+    DuplicatedLabel(Identifier),
+    DuplicatedChoice(Identifier),
+    /// Choice or menu has more than one option with the same name.
+    DuplicatedOption(Identifier),
+    UndefinedLabel(Identifier),
+    UndefinedChoice(Identifier),
+    DuplicatedCharacter(Identifier),
+    /// Speaker isn't declared with `define_character`.
+    UndefinedCharacter(Identifier),
+    /// Field of `define_character` is repeated or has a wrong value.
+    BadCharacter(String),
+    /// Character | portrait that isn't in `define_portraits` of the character.
+    UndefinedPortrait(Identifier, Identifier),
+    /// `return` belongs to a label that is never called.
+    ReturnOutsideCall(Identifier),
+    /// Imported file can't be loaded or used.
+    BadImport(String),
+    /// Files import each other, holds the chain of files.
+    ImportCycle(String),
+    /// Literal is grammatically correct, but can't be represented.
+    BadLiteral(String),
+    /// Operation is applied to values it can't work with.
+    TypeMismatch(String),
+    /// Expression is nested too deep to be loaded back from a compiled script.
+    ExpressionTooDeep,
+    /// Variable placeholder inside of a text is malformed.
+    BadInterpolation(String),
+    /// Style tags inside of a text are malformed.
+    BadMarkup(String),
+    /// Escape sequence inside of a string is unknown or malformed.
+    BadEscape(String),
+    /// Word after `# @tag` or `# id:` of the line is neither a tag nor an id.
+    BadTag(String),
+    /// The same `#id:` is given to more than one line.
+    DuplicatedLineId(Identifier),
+    /// Translation file is malformed or has a text that can't be shown.
+    BadTranslation(String),
+    /// Compiled script is corrupted or made by another version of the format.
+    BadCompiledScript(String),
+    /// Execution snapshot is corrupted or doesn't fit the script.
+    BadSnapshot(String),
+    /// Snapshot was taken while another version of the script was running.
+    ScriptChanged,
+}
+
+/// Place in source where error was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column number (in characters).
+    pub column: usize,
+    /// Byte range of offending part inside the source.
+    pub range: (usize, usize),
+    /// Whole source line containing the error.
+    pub snippet: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialogError {
+    kind: ErrorKind,
+    location: Option<Location>,
+    file: Option<Rc<Path>>,
+}
+
+impl DialogError {
+    pub(crate) fn new(kind: ErrorKind, location: Option<Location>) -> Self {
+        Self {
+            kind,
+            location,
+            file: None,
+        }
+    }
+
+    /// Remembers file the error came from, unless it is already known.
+    pub(crate) fn in_file(mut self, file: &Rc<Path>) -> Self {
+        self.file.get_or_insert_with(|| file.clone());
+        self
+    }
+
+    pub(crate) fn at(kind: ErrorKind, span: pest::Span<'_>) -> Self {
+        Self::new(kind, Some(span.into()))
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Location is absent for errors found in AST that wasn't produced by the parser.
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    /// File is known only for errors found by project loader.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+}
+
+impl From<pest::Span<'_>> for Location {
+    fn from(span: pest::Span<'_>) -> Self {
+        let start = span.start_pos();
+        let (line, column) = start.line_col();
+        let snippet = start.line_of().trim_end_matches(['\r', '\n']).to_owned();
+        Self {
+            line,
+            column,
+            range: (span.start(), span.end()),
+            snippet,
+        }
+    }
+}
+
+impl From<pest::error::Error<Rule>> for DialogError {
+    fn from(error: pest::error::Error<Rule>) -> Self {
+        let (line, column) = match error.line_col {
+            LineColLocation::Pos(pos) => pos,
+            LineColLocation::Span(start, _) => start,
+        };
+        let range = match error.location {
+            InputLocation::Pos(pos) => (pos, pos),
+            InputLocation::Span(span) => span,
+        };
+        let location = Location {
+            line,
+            column,
+            range,
+            snippet: error.line().to_owned(),
+        };
+        let kind = ErrorKind::Syntax(error.variant.message().into_owned());
+        Self::new(kind, Some(location))
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Syntax(message) => write!(f, "syntax error: {message}"),
+            ErrorKind::DuplicatedLabel(ident) => {
+                write!(f, "label `{}` is defined more than once", ident.as_str())
+            }
+            ErrorKind::DuplicatedChoice(ident) => {
+                write!(f, "choice `{}` is defined more than once", ident.as_str())
+            }
+            ErrorKind::DuplicatedOption(ident) => {
+                write!(f, "option `{}` is offered more than once", ident.as_str())
+            }
+            ErrorKind::UndefinedLabel(ident) => {
+                write!(f, "label `{}` is never defined", ident.as_str())
+            }
+            ErrorKind::UndefinedChoice(ident) => {
+                write!(f, "choice `{}` is never defined", ident.as_str())
+            }
+            ErrorKind::DuplicatedCharacter(ident) => {
+                write!(
+                    f,
+                    "character `{}` is defined more than once",
+                    ident.as_str()
+                )
+            }
+            ErrorKind::UndefinedCharacter(ident) => {
+                write!(f, "character `{}` is never defined", ident.as_str())
+            }
+            ErrorKind::BadCharacter(message) => write!(f, "bad character: {message}"),
+            ErrorKind::UndefinedPortrait(who, portrait) => write!(
+                f,
+                "portrait `{}` of `{}` is never defined",
+                portrait.as_str(),
+                who.as_str()
+            ),
+            ErrorKind::ReturnOutsideCall(ident) => write!(
+                f,
+                "`return` inside of label `{}` that is never called",
+                ident.as_str()
+            ),
+            ErrorKind::BadImport(message) => write!(f, "bad import: {message}"),
+            ErrorKind::ImportCycle(chain) => write!(f, "files import each other: {chain}"),
+            ErrorKind::BadLiteral(literal) => write!(f, "invalid literal `{literal}`"),
+            ErrorKind::TypeMismatch(message) => write!(f, "type mismatch: {message}"),
+            ErrorKind::ExpressionTooDeep => write!(
+                f,
+                "expression is nested deeper than {MAX_EXPRESSION_DEPTH} levels"
+            ),
+            ErrorKind::BadInterpolation(message) => write!(f, "bad interpolation: {message}"),
+            ErrorKind::BadMarkup(message) => write!(f, "bad markup: {message}"),
+            ErrorKind::BadEscape(escape) => write!(f, "invalid escape sequence `{escape}`"),
+            ErrorKind::BadTag(tag) => write!(
+                f,
+                "invalid tag `{tag}`, expected `@name`, `@name:value` or `id:name` of a line"
+            ),
+            ErrorKind::DuplicatedLineId(ident) => {
+                write!(f, "line id `{}` is used more than once", ident.as_str())
+            }
+            ErrorKind::BadTranslation(message) => write!(f, "bad translation: {message}"),
+            ErrorKind::BadCompiledScript(message) => write!(f, "bad compiled script: {message}"),
+            ErrorKind::BadSnapshot(message) => write!(f, "bad snapshot: {message}"),
+            ErrorKind::ScriptChanged => {
+                write!(f, "script has changed since the snapshot was taken")
+            }
+        }
+    }
+}
+
+impl Display for DialogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error: {}", self.kind)?;
+        let file = self
+            .file
+            .as_ref()
+            .map(|file| format!("{}:", file.display()))
+            .unwrap_or_default();
+        if let (Some(file), None) = (&self.file, &self.location) {
+            write!(f, "\n --> {}", file.display())?;
+        }
+        if let Some(location) = &self.location {
+            let line_no = location.line.to_string();
+            let pad = " ".repeat(line_no.len());
+            let underline = {
+                let start = location.column.saturating_sub(1);
+                let len = location.range.1 - location.range.0;
+                // Underline only the part of the span that fits on the first line
+                let width = location
+                    .snippet
+                    .chars()
+                    .skip(start)
+                    .scan(0, |sum, ch| {
+                        *sum += ch.len_utf8();
+                        Some(*sum)
+                    })
+                    .take_while(|sum| *sum <= len)
+                    .count()
+                    .max(1);
+                " ".repeat(start) + &"^".repeat(width)
+            };
+            write!(
+                f,
+                "\n{pad}--> {file}{}:{}\n{pad} |\n{line_no} | {}\n{pad} | {underline}",
+                location.line, location.column, location.snippet
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DialogError {}
@@ -1,14 +1,41 @@
-use std::rc::Rc;
+use std::{rc::Rc, sync::LazyLock};
 
-use pest::{Parser, error::Error, iterators::Pair};
+use pest::{
+    Parser, Span,
+    iterators::Pair,
+    pratt_parser::{Assoc, Op, PrattParser},
+};
 use pest_derive::Parser;
 
-use crate::utils::UniquePush;
+use crate::{
+    error::{DialogError, ErrorKind, Location},
+    markup::{is_valid_color, join_parts, parse_markup},
+    template::parse_template,
+    utils::{UniquePush, fnv1a},
+};
 
 #[derive(Parser)]
 #[grammar = "direct_script.pest"]
 struct DirectScriptParser;
 
+static PRATT_PARSER: LazyLock<PrattParser<Rule>> = LazyLock::new(|| {
+    // From the lowest precedence to the highest
+    PrattParser::new()
+        .op(Op::infix(Rule::op_or, Assoc::Left))
+        .op(Op::infix(Rule::op_and, Assoc::Left))
+        .op(Op::prefix(Rule::op_not))
+        .op(Op::infix(Rule::op_eq, Assoc::Left) | Op::infix(Rule::op_neq, Assoc::Left))
+        .op(Op::infix(Rule::op_lt, Assoc::Left)
+            | Op::infix(Rule::op_le, Assoc::Left)
+            | Op::infix(Rule::op_gt, Assoc::Left)
+            | Op::infix(Rule::op_ge, Assoc::Left))
+        .op(Op::infix(Rule::op_add, Assoc::Left) | Op::infix(Rule::op_sub, Assoc::Left))
+        .op(Op::infix(Rule::op_mul, Assoc::Left)
+            | Op::infix(Rule::op_div, Assoc::Left)
+            | Op::infix(Rule::op_rem, Assoc::Left))
+        .op(Op::prefix(Rule::op_neg))
+});
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Identifier(Rc<str>);
 
@@ -19,8 +46,21 @@ pub struct Text(Rc<str>);
 pub enum Command {
     End,
     Jump(Identifier),
-    Choice(Identifier, Identifier),
-    Trigger(Identifier),
+    /// Jump that comes back to the next command on `return`
+    Call(Identifier),
+    Return,
+    /// where to store | what | tags
+    Choice(Identifier, Identifier, Vec<(Identifier, Option<Text>)>),
+    /// what | arguments | where to store the result
+    Trigger(Identifier, Vec<Expression>, Option<Identifier>),
+    /// Pause in seconds
+    Wait(f32),
+    /// variable = value
+    Set(Identifier, Expression),
+    /// variable += value
+    Add(Identifier, Expression),
+    /// variable -= value
+    Sub(Identifier, Expression),
 }
 
 #[derive(Debug)]
@@ -29,31 +69,121 @@ pub enum Variable {
     Boolean(bool),
     String(Text),
     Int(i32),
+    Float(f64),
 }
 
-#[derive(Debug)]
-pub enum LogicOperation {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOperation {
+    Not,
+    Negate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOperation {
+    Or,
+    And,
     Equal,
     NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
 }
 
+/// How deep expressions can be nested, compiled scripts read them back recursively.
+pub(crate) const MAX_EXPRESSION_DEPTH: usize = 256;
+
 #[derive(Debug)]
-pub enum Condition {
+pub enum Expression {
     Variable(Variable),
-    Expr(Variable, LogicOperation, Variable),
+    Unary(UnaryOperation, Box<Expression>),
+    Binary(Box<Expression>, BinaryOperation, Box<Expression>),
+    /// `random(min, max)`
+    Random(Box<Expression>, Box<Expression>),
+    /// How many times the label was entered, `visited(label)` is `visits(label) > 0`
+    Visits(Identifier),
+}
+
+impl Expression {
+    /// Operations on the longest way from the root to a value, counting both ends.
+    fn depth(&self) -> usize {
+        match self {
+            Expression::Variable(_) | Expression::Visits(_) => 1,
+            Expression::Unary(_, operand) => operand.depth() + 1,
+            Expression::Binary(lhs, _, rhs) | Expression::Random(lhs, rhs) => {
+                lhs.depth().max(rhs.depth()) + 1
+            }
+        }
+    }
+}
+
+/// When option of a choice can be picked.
+#[derive(Debug)]
+pub enum OptionCondition {
+    Always,
+    /// Option is hidden while condition is false
+    ShownIf(Expression),
+    /// Option is shown, but can't be picked while condition is false
+    EnabledIf(Expression),
+}
+
+/// Character declared with `define_character`.
+#[derive(Clone, Debug)]
+pub struct Character {
+    /// Shown in the name box instead of id
+    pub name: Text,
+    /// Color of the name, same as in `[color=...]`
+    pub color: Option<Text>,
+    /// Shown when the line doesn't have its own portrait
+    pub portrait: Option<Identifier>,
+    /// Sound played while the text appears
+    pub blip: Option<Text>,
 }
 
 #[derive(Debug)]
 pub enum AstNode {
     Label(Identifier),
     Command(Command),
-    Dialog(Option<Identifier>, Vec<Text>),
-    Choices(Identifier, Vec<(Identifier, Text)>),
+    /// who | portrait | what | line id | tags with optional values
+    Dialog(
+        Option<Identifier>,
+        Option<Identifier>,
+        Vec<Text>,
+        Identifier,
+        Vec<(Identifier, Option<Text>)>,
+    ),
+    Choices(Identifier, Vec<(Identifier, Text, OptionCondition)>),
+    Character(Identifier, Character),
     LabelBlock(Identifier, Vec<AstNode>),
-    IfBlock(Condition, Vec<AstNode>, Option<Vec<AstNode>>),
+    /// `if` and `else if` branches in order, followed by optional `else` part
+    IfBlock(Vec<(Expression, Vec<AstNode>)>, Option<Vec<AstNode>>),
+    /// Weight and body of every branch
+    Random(Vec<(u32, Vec<AstNode>)>),
+    /// Body runs only on the first visit of the label
+    Once(Identifier, Vec<AstNode>),
+    /// Where to store the answer | id of the menu | tags | options with their bodies
+    Menu(
+        Identifier,
+        Identifier,
+        Vec<(Identifier, Option<Text>)>,
+        Vec<(Identifier, Text, OptionCondition, Vec<AstNode>)>,
+    ),
+}
+
+/// Parses single self-contained file, use project loader for files with imports.
+pub fn parse_to_ast(source: &str) -> Result<Vec<AstNode>, DialogError> {
+    let (ast_tree, context) = parse_module(source)?;
+    context.finalize()?;
+    Ok(ast_tree)
 }
 
-pub fn parse_to_ast(source: &str) -> Result<Vec<AstNode>, Error<Rule>> {
+/// Parses one file of a project, references to other files are left unchecked.
+pub(crate) fn parse_module(source: &str) -> Result<(Vec<AstNode>, ParserContext), DialogError> {
     let pairs = DirectScriptParser::parse(Rule::direct_script, source)?;
 
     let mut ast_tree = Vec::new();
@@ -61,8 +191,26 @@ pub fn parse_to_ast(source: &str) -> Result<Vec<AstNode>, Error<Rule>> {
 
     for pair in pairs {
         let node = match pair.as_rule() {
-            Rule::label_block => build_ast_from_label_block(pair, &mut context),
-            Rule::choice_decl => build_ast_from_choice_decl(pair, &mut context),
+            Rule::import_decl => {
+                let path = pair.into_inner().next().unwrap();
+                let span = path.as_span();
+                context.imports.push((path.try_into()?, span.into()));
+                continue;
+            }
+            Rule::portraits_decl => {
+                let mut inner = pair.into_inner();
+                let who = inner.next().unwrap();
+                let span = who.as_span();
+                let who: Identifier = who.into();
+                context.demand_character(&who, span);
+                for portrait in inner {
+                    context.declare_portrait(&who, &portrait.into());
+                }
+                continue;
+            }
+            Rule::label_block => build_ast_from_label_block(pair, &mut context)?,
+            Rule::choice_decl => build_ast_from_choice_decl(pair, &mut context)?,
+            Rule::character_decl => build_ast_from_character_decl(pair, &mut context)?,
             Rule::EOI => continue,
             _ => panic!(
                 "Unexpected declaration in global scope: {:?}",
@@ -72,21 +220,35 @@ pub fn parse_to_ast(source: &str) -> Result<Vec<AstNode>, Error<Rule>> {
         ast_tree.push(node);
     }
 
-    if !context.finalize() {
-        dbg!(&context);
-        panic!("Context isn't complete!");
-    }
+    context.finalize_local()?;
 
-    Ok(ast_tree)
+    Ok((ast_tree, context))
 }
 
 #[derive(Debug, Default)]
-struct ParserContext {
-    decl_labels: Vec<Identifier>,
-    decl_choices: Vec<Identifier>,
-    expected_labels: Vec<Identifier>,
-    expected_choices: Vec<Identifier>,
+pub(crate) struct ParserContext {
+    pub decl_labels: Vec<Identifier>,
+    pub decl_choices: Vec<Identifier>,
+    pub expected_labels: Vec<(Identifier, Location)>,
+    pub expected_choices: Vec<(Identifier, Location)>,
+    /// Characters are shared by all files of a project
+    pub decl_characters: Vec<(Identifier, Location)>,
+    pub expected_characters: Vec<(Identifier, Location)>,
+    /// Character | portrait
+    pub decl_portraits: Vec<(Identifier, Identifier)>,
+    pub expected_portraits: Vec<((Identifier, Identifier), Location)>,
     invoked_ident: Vec<Identifier>,
+    pub called_labels: Vec<Identifier>,
+    /// Every `return` with labels that run into it, the label of the block goes first
+    pub returns: Vec<(Vec<Identifier>, Location)>,
+    /// The last declared label, it owns following commands up to the end of its block
+    current_label: Option<Identifier>,
+    /// Label of the current block and inline labels declared in it so far
+    block_labels: Vec<Identifier>,
+    /// Ids of lines and menus, translations refer to them
+    line_ids: Vec<Identifier>,
+    /// Path as written | where
+    pub imports: Vec<(Text, Location)>,
 }
 
 impl ParserContext {
@@ -96,49 +258,228 @@ impl ParserContext {
         }
     }
 
-    fn declare_label(&mut self, label: &Identifier) -> Result<(), ()> {
+    fn declare_label(&mut self, label: &Identifier, span: Span<'_>) -> Result<(), DialogError> {
         if self.decl_labels.contains(label) {
-            Err(())
+            Err(DialogError::at(
+                ErrorKind::DuplicatedLabel(label.clone()),
+                span,
+            ))
         } else {
             self.decl_labels.push(label.clone());
+            self.current_label = Some(label.clone());
+            self.block_labels.push(label.clone());
             Ok(())
         }
     }
 
-    fn declare_choice(&mut self, choice: &Identifier) -> Result<(), ()> {
+    fn declare_choice(&mut self, choice: &Identifier, span: Span<'_>) -> Result<(), DialogError> {
         if self.decl_choices.contains(choice) {
-            Err(())
+            Err(DialogError::at(
+                ErrorKind::DuplicatedChoice(choice.clone()),
+                span,
+            ))
         } else {
             self.decl_choices.push(choice.clone());
             Ok(())
         }
     }
 
+    fn declare_character(&mut self, who: &Identifier, span: Span<'_>) -> Result<(), DialogError> {
+        if self.decl_characters.iter().any(|(item, _)| item == who) {
+            Err(DialogError::at(
+                ErrorKind::DuplicatedCharacter(who.clone()),
+                span,
+            ))
+        } else {
+            self.decl_characters.push((who.clone(), span.into()));
+            Ok(())
+        }
+    }
+
+    fn demand_character(&mut self, who: &Identifier, span: Span<'_>) {
+        if !self.expected_characters.iter().any(|(item, _)| item == who) {
+            self.expected_characters.push((who.clone(), span.into()));
+        }
+    }
+
+    fn declare_portrait(&mut self, who: &Identifier, portrait: &Identifier) {
+        self.decl_portraits
+            .push_unique((who.clone(), portrait.clone()));
+    }
+
+    fn demand_portrait(&mut self, who: &Identifier, portrait: &Identifier, span: Span<'_>) {
+        let pair = (who.clone(), portrait.clone());
+        if !self
+            .expected_portraits
+            .iter()
+            .any(|(item, _)| *item == pair)
+        {
+            self.expected_portraits.push((pair, span.into()));
+        }
+    }
+
+    /// Explicit id has to be unique. Generated one comes from the label and the text,
+    /// so it stays the same while they don't change.
+    fn declare_line_id(
+        &mut self,
+        explicit: Option<(Identifier, Span<'_>)>,
+        text: &str,
+    ) -> Result<Identifier, DialogError> {
+        let id = match explicit {
+            Some((id, span)) if self.line_ids.contains(&id) => {
+                return Err(DialogError::at(ErrorKind::DuplicatedLineId(id), span));
+            }
+            Some((id, _)) => id,
+            None => {
+                let label = self
+                    .current_label
+                    .as_ref()
+                    .expect("Lines are always inside of a label");
+                self.unique_line_id(format!("{}.{:08x}", label.as_str(), fnv1a(text.as_bytes())))
+            }
+        };
+        self.line_ids.push(id.clone());
+        Ok(id)
+    }
+
+    fn declare_menu_id(&mut self, store_to: &Identifier) -> Identifier {
+        let label = self
+            .current_label
+            .as_ref()
+            .expect("Menus are always inside of a label");
+        let id = self.unique_line_id(format!("{}@{}", label.as_str(), store_to.as_str()));
+        self.line_ids.push(id.clone());
+        id
+    }
+
+    /// Repeated ids get a number: `label.1a2b3c4d:2`.
+    fn unique_line_id(&self, base: String) -> Identifier {
+        let mut id: Identifier = base.as_str().into();
+        let mut number = 1;
+        while self.line_ids.contains(&id) {
+            number += 1;
+            id = format!("{base}:{number}").as_str().into();
+        }
+        id
+    }
+
     fn declare_invocation(&mut self, invoked: &Identifier) {
         self.invoked_ident.push_unique(invoked);
     }
 
-    fn demand_label(&mut self, label: &Identifier) {
-        self.expected_labels.push_unique(label);
+    fn demand_label(&mut self, label: &Identifier, span: Span<'_>) {
+        // Only first usage is remembered, it is the one to be reported
+        if !self.expected_labels.iter().any(|(item, _)| item == label) {
+            self.expected_labels.push((label.clone(), span.into()));
+        }
+    }
+
+    fn demand_call(&mut self, label: &Identifier, span: Span<'_>) {
+        self.demand_label(label, span);
+        self.called_labels.push_unique(label);
+    }
+
+    /// Execution gets to `return` from any label above it in the block,
+    /// it is fine as long as one of them is called.
+    fn declare_return(&mut self, span: Span<'_>) {
+        assert!(
+            !self.block_labels.is_empty(),
+            "Commands are always inside of a label"
+        );
+        self.returns.push((self.block_labels.clone(), span.into()));
+    }
+
+    fn demand_choice(&mut self, choice: &Identifier, span: Span<'_>) {
+        if !self.expected_choices.iter().any(|(item, _)| item == choice) {
+            self.expected_choices.push((choice.clone(), span.into()));
+        }
     }
 
-    fn demand_choice(&mut self, choice: &Identifier) {
-        self.expected_choices.push_unique(choice);
+    /// Checks references that don't lead to other files.
+    fn finalize_local(&self) -> Result<(), DialogError> {
+        let missing_label = self
+            .expected_labels
+            .iter()
+            .find(|(val, _)| !val.is_qualified() && !self.decl_labels.contains(val))
+            .map(|(ident, location)| (ErrorKind::UndefinedLabel(ident.clone()), location));
+        let missing_choice = self
+            .expected_choices
+            .iter()
+            .find(|(val, _)| !val.is_qualified() && !self.decl_choices.contains(val))
+            .map(|(ident, location)| (ErrorKind::UndefinedChoice(ident.clone()), location));
+        report_first([missing_label, missing_choice])
     }
 
-    fn finalize(&self) -> bool {
-        // TODO: check if valid context
-        self.expected_labels
+    /// Checks the file as if it is the whole script.
+    fn finalize(&self) -> Result<(), DialogError> {
+        self.finalize_local()?;
+        let missing_label = self
+            .expected_labels
             .iter()
-            .all(|val| self.decl_labels.contains(val))
-            && self
-                .expected_choices
-                .iter()
-                .all(|val| self.decl_choices.contains(val))
+            .find(|(val, _)| val.is_qualified())
+            .map(|(ident, location)| (ErrorKind::UndefinedLabel(ident.clone()), location));
+        let missing_choice = self
+            .expected_choices
+            .iter()
+            .find(|(val, _)| val.is_qualified())
+            .map(|(ident, location)| (ErrorKind::UndefinedChoice(ident.clone()), location));
+        let stray_return = self
+            .returns
+            .iter()
+            .find(|(labels, _)| {
+                !labels
+                    .iter()
+                    .any(|label| self.called_labels.contains(label))
+            })
+            .map(|(labels, location)| (ErrorKind::ReturnOutsideCall(labels[0].clone()), location));
+        let missing_character = self
+            .expected_characters
+            .iter()
+            .find(|(who, _)| !self.decl_characters.iter().any(|(item, _)| item == who))
+            .map(|(who, location)| (ErrorKind::UndefinedCharacter(who.clone()), location));
+        let missing_portrait = self
+            .expected_portraits
+            .iter()
+            .find(|(pair, _)| !self.decl_portraits.contains(pair))
+            .map(|((who, portrait), location)| {
+                (
+                    ErrorKind::UndefinedPortrait(who.clone(), portrait.clone()),
+                    location,
+                )
+            });
+        let import = self.imports.first().map(|(path, location)| {
+            let message = format!("`{}` can be imported only by project loader", path.as_str());
+            (ErrorKind::BadImport(message), location)
+        });
+        report_first([
+            missing_label,
+            missing_choice,
+            stray_return,
+            missing_character,
+            missing_portrait,
+            import,
+        ])
     }
 }
 
-fn build_ast_from_label_block(block: Pair<'_, Rule>, context: &mut ParserContext) -> AstNode {
+/// Reports the error that comes first in the source.
+pub(crate) fn report_first<'a>(
+    errors: impl IntoIterator<Item = Option<(ErrorKind, &'a Location)>>,
+) -> Result<(), DialogError> {
+    let first = errors
+        .into_iter()
+        .flatten()
+        .min_by_key(|(_, location)| location.range.0);
+    match first {
+        Some((kind, location)) => Err(DialogError::new(kind, Some(location.clone()))),
+        None => Ok(()),
+    }
+}
+
+fn build_ast_from_label_block(
+    block: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
     let mut inner = block.into_inner();
     let main_label = inner
         .next()
@@ -148,20 +489,23 @@ fn build_ast_from_label_block(block: Pair<'_, Rule>, context: &mut ParserContext
         Rule::label,
         "Label is expected to be first thing in block"
     );
+    let span = main_label.as_span();
     let ident: Identifier = main_label.into();
-    context
-        .declare_label(&ident)
-        .expect("Duplicated label block!");
+    context.block_labels.clear();
+    context.declare_label(&ident, span)?;
 
     let mut content = Vec::new();
     for pair in inner {
-        let node = parse_block_content(pair, context);
+        let node = parse_block_content(pair, context)?;
         content.push(node);
     }
-    AstNode::LabelBlock(ident, content)
+    Ok(AstNode::LabelBlock(ident, content))
 }
 
-fn build_ast_from_choice_decl(decl: Pair<'_, Rule>, context: &mut ParserContext) -> AstNode {
+fn build_ast_from_choice_decl(
+    decl: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
     let mut inner = decl.into_inner();
     let name = inner.next().unwrap();
     assert_eq!(
@@ -170,158 +514,725 @@ fn build_ast_from_choice_decl(decl: Pair<'_, Rule>, context: &mut ParserContext)
         "Choice declaration is expected to have a name!"
     );
 
+    let span = name.as_span();
     let name: Identifier = name.as_str().into();
-    context
-        .declare_choice(&name)
-        .expect("Duplicated choice block!");
+    context.declare_choice(&name, span)?;
 
-    let mut declared = Vec::new();
-    for pair in inner {
-        assert_eq!(pair.as_rule(), Rule::decl_inner);
-        let mut inner = pair.into_inner();
-        let name: Identifier = inner.next().unwrap().as_str().into();
-        let text: Text = inner.next().unwrap().into();
-        declared.push((name, text));
-    }
-    AstNode::Choices(name, declared)
+    let mut names = Vec::new();
+    let declared = inner
+        .map(|option| parse_option(option, &mut names, context))
+        .collect::<Result<_, _>>()?;
+    Ok(AstNode::Choices(name, declared))
 }
 
-fn parse_if_block(block: Pair<'_, Rule>, context: &mut ParserContext) -> AstNode {
-    let mut inner = block.into_inner();
-    let condition = inner.next().unwrap();
-    assert_eq!(
-        condition.as_rule(),
-        Rule::condition,
-        "If block is expected to have condition!"
-    );
+fn build_ast_from_character_decl(
+    decl: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
+    let mut inner = decl.into_inner();
+    let who = inner.next().unwrap();
+    let span = who.as_span();
+    let who: Identifier = who.into();
+    context.declare_character(&who, span)?;
 
-    let condition = {
-        let mut inner = condition.into_inner();
-        let (size, _) = inner.size_hint();
-        match size {
-            1 => Condition::Variable(parse_variable(inner.next().unwrap())),
-            3 => {
-                let rhs = parse_variable(inner.next().unwrap());
-                let op = parse_logic_op(inner.next().unwrap());
-                let lhs = parse_variable(inner.next().unwrap());
-                Condition::Expr(rhs, op, lhs)
+    let mut character = Character {
+        name: who.as_str().into(),
+        color: None,
+        portrait: None,
+        blip: None,
+    };
+    let mut seen = Vec::new();
+    for field in inner {
+        let rule = field.as_rule();
+        let span = field.as_span();
+        if seen.contains(&rule) {
+            let key = match rule {
+                Rule::character_name => "name",
+                Rule::character_color => "color",
+                Rule::character_portrait => "portrait",
+                _ => "blip",
+            };
+            let message = format!("`{key}` is set more than once");
+            return Err(DialogError::at(ErrorKind::BadCharacter(message), span));
+        }
+        seen.push(rule);
+        let value = field.into_inner().next().unwrap();
+        match rule {
+            Rule::character_name => character.name = value.try_into()?,
+            Rule::character_color => {
+                let value_span = value.as_span();
+                let color: Text = value.try_into()?;
+                if !is_valid_color(color.as_str()) {
+                    let message = format!("`{}` is not a color", color.as_str());
+                    return Err(DialogError::at(
+                        ErrorKind::BadCharacter(message),
+                        value_span,
+                    ));
+                }
+                character.color = Some(color);
+            }
+            Rule::character_portrait => {
+                let portrait = value.into();
+                context.demand_portrait(&who, &portrait, span);
+                character.portrait = Some(portrait);
             }
-            _ => panic!("Unexpected condition structure!"),
+            Rule::character_blip => character.blip = Some(value.try_into()?),
+            _ => panic!("Unexpected field of character: {rule:?}"),
         }
+    }
+    Ok(AstNode::Character(who, character))
+}
+
+/// Option names are the answers and ids of translations, so they can't repeat
+/// among `names` of the same choice.
+fn parse_option(
+    option: Pair<'_, Rule>,
+    names: &mut Vec<Identifier>,
+    context: &mut ParserContext,
+) -> Result<(Identifier, Text, OptionCondition), DialogError> {
+    assert_eq!(option.as_rule(), Rule::decl_inner);
+    let mut inner = option.into_inner();
+    let name = inner.next().unwrap();
+    let span = name.as_span();
+    let name: Identifier = name.as_str().into();
+    if names.contains(&name) {
+        return Err(DialogError::at(ErrorKind::DuplicatedOption(name), span));
+    }
+    names.push(name.clone());
+    let text: Text = inner.next().unwrap().try_into()?;
+    let condition = match inner.next() {
+        Some(condition) => {
+            let mut inner = condition.into_inner();
+            let expression = parse_expression(inner.next().unwrap(), context)?;
+            if inner.next().is_some() {
+                OptionCondition::EnabledIf(expression)
+            } else {
+                OptionCondition::ShownIf(expression)
+            }
+        }
+        None => OptionCondition::Always,
     };
+    Ok((name, text, condition))
+}
 
-    let mut content = Vec::new();
+fn parse_menu_block(
+    block: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
+    let mut inner = block.into_inner();
+    let mut header = inner.next().unwrap().into_inner();
+    let store_to: Identifier = header.next().unwrap().into();
+    let tags = header
+        .next()
+        .map(parse_tags)
+        .transpose()?
+        .unwrap_or_default();
+    let id = context.declare_menu_id(&store_to);
+    let enclosing = context.current_label.clone();
+    let mut names = Vec::new();
+    let mut options = Vec::new();
+    for option in inner {
+        assert_eq!(option.as_rule(), Rule::menu_option);
+        let mut inner = option.into_inner();
+        let (name, text, condition) = parse_option(inner.next().unwrap(), &mut names, context)?;
+        let body = inner
+            .map(|pair| parse_block_content(pair, context))
+            .collect::<Result<_, _>>()?;
+        // Inline label of an option doesn't own anything after it
+        context.current_label = enclosing.clone();
+        options.push((name, text, condition, body));
+    }
+    Ok(AstNode::Menu(store_to, id, tags, options))
+}
+
+fn parse_if_block(
+    block: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
+    let mut inner = block.into_inner();
+    let condition = parse_expression(inner.next().unwrap(), context)?;
+
+    // Inline labels of a branch own commands only up to the end of the branch
+    let enclosing = context.current_label.clone();
+    let mut branches = vec![(condition, Vec::new())];
     let mut else_part = None;
     for pair in inner {
-        let node = match pair.as_rule() {
+        match pair.as_rule() {
+            Rule::else_if_part => {
+                assert!(else_part.is_none(), "Got else if after else part??");
+                context.current_label = enclosing.clone();
+                let mut inner = pair.into_inner();
+                let condition = parse_expression(inner.next().unwrap(), context)?;
+                let mut content = Vec::new();
+                for pair in inner {
+                    let node = parse_block_content(pair, context)?;
+                    content.push(node);
+                }
+                branches.push((condition, content));
+            }
             Rule::else_part => {
                 assert!(else_part.is_none(), "Got two else part in single if??");
+                context.current_label = enclosing.clone();
                 let mut else_content = Vec::new();
                 for pair in pair.into_inner() {
-                    let node = parse_block_content(pair, context);
+                    let node = parse_block_content(pair, context)?;
                     else_content.push(node);
                 }
                 else_part = Some(else_content);
-                continue;
             }
-            _ => parse_block_content(pair, context),
-        };
-        content.push(node);
+            _ => {
+                let node = parse_block_content(pair, context)?;
+                branches.last_mut().unwrap().1.push(node);
+            }
+        }
+    }
+    context.current_label = enclosing;
+    Ok(AstNode::IfBlock(branches, else_part))
+}
+
+fn parse_random_block(
+    block: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
+    // Inline labels of a branch own commands only up to the end of the branch
+    let enclosing = context.current_label.clone();
+    let mut branches = vec![(1, Vec::new())];
+    for pair in block.into_inner() {
+        match pair.as_rule() {
+            Rule::random_weight => branches.last_mut().unwrap().0 = parse_weight(pair)?,
+            Rule::random_branch => {
+                context.current_label = enclosing.clone();
+                let mut branch = (1, Vec::new());
+                for pair in pair.into_inner() {
+                    match pair.as_rule() {
+                        Rule::random_weight => branch.0 = parse_weight(pair)?,
+                        _ => branch.1.push(parse_block_content(pair, context)?),
+                    }
+                }
+                branches.push(branch);
+            }
+            _ => {
+                let node = parse_block_content(pair, context)?;
+                branches.last_mut().unwrap().1.push(node);
+            }
+        }
+    }
+    context.current_label = enclosing;
+    Ok(AstNode::Random(branches))
+}
+
+fn parse_once_block(
+    block: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
+    let label = context
+        .current_label
+        .clone()
+        .expect("Blocks are always inside of a label");
+    let body = block
+        .into_inner()
+        .map(|pair| parse_block_content(pair, context))
+        .collect::<Result<_, _>>()?;
+    context.current_label = Some(label.clone());
+    Ok(AstNode::Once(label, body))
+}
+
+/// Weight of random branch has to be positive.
+fn parse_weight(weight: Pair<'_, Rule>) -> Result<u32, DialogError> {
+    let literal = weight.into_inner().next().unwrap();
+    match literal.as_str().parse() {
+        Ok(weight) if weight > 0 => Ok(weight),
+        _ => Err(DialogError::at(
+            ErrorKind::BadLiteral(literal.as_str().into()),
+            literal.as_span(),
+        )),
     }
-    AstNode::IfBlock(condition, content, else_part)
 }
 
-fn parse_block_content(pair: Pair<'_, Rule>, context: &mut ParserContext) -> AstNode {
+fn parse_block_content(
+    pair: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
     match pair.as_rule() {
         Rule::label => parse_label(pair, context),
         Rule::dialog => parse_dialog(pair, context),
         Rule::command => parse_command(pair, context),
         Rule::if_block => parse_if_block(pair, context),
+        Rule::random_block => parse_random_block(pair, context),
+        Rule::once_block => parse_once_block(pair, context),
+        Rule::menu_block => parse_menu_block(pair, context),
         _ => panic!("Unexpected token inside a block: {:?}", pair.as_rule()),
     }
 }
 
-fn parse_label(label: Pair<'_, Rule>, context: &mut ParserContext) -> AstNode {
+fn parse_label(label: Pair<'_, Rule>, context: &mut ParserContext) -> Result<AstNode, DialogError> {
+    let span = label.as_span();
     let ident: Identifier = label.into();
-    context
-        .declare_label(&ident)
-        .expect("Detected duplicated label!");
-    AstNode::Label(ident)
+    context.declare_label(&ident, span)?;
+    Ok(AstNode::Label(ident))
 }
 
-fn parse_dialog(dialog: Pair<'_, Rule>, _context: &mut ParserContext) -> AstNode {
+fn parse_dialog(
+    dialog: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
     assert_eq!(dialog.as_rule(), Rule::dialog);
     let mut name = None;
+    let mut portrait = None;
+    let mut line_id = None;
+    let mut tags = Vec::new();
     let mut content: Vec<String> = Vec::new();
     for pair in dialog.into_inner() {
         match pair.as_rule() {
-            Rule::name => name = Some(pair.as_str().into()),
+            Rule::name => {
+                let who = pair.as_str().into();
+                context.demand_character(&who, pair.as_span());
+                name = Some(who);
+            }
+            Rule::portrait => {
+                let span = pair.as_span();
+                let tag = pair.into_inner().next().unwrap().into();
+                let who = name.as_ref().expect("Portrait always follows a name");
+                context.demand_portrait(who, &tag, span);
+                portrait = Some(tag);
+            }
+            Rule::line_meta => {
+                for item in pair.into_inner() {
+                    match item.as_rule() {
+                        Rule::line_id => {
+                            let id = item.into_inner().next().unwrap();
+                            line_id = Some((id.as_str().into(), id.as_span()));
+                        }
+                        _ => tags.push(parse_tag(item)?),
+                    }
+                }
+            }
             Rule::sep_line => content.last_mut().unwrap().push(' '),
             Rule::sep_break => content.last_mut().unwrap().push('\n'),
             Rule::string => {
                 let inner = pair.into_inner().next().unwrap();
-                content.push(inner.as_str().to_owned());
+                let (text, origins) = decode_escapes(&inner)?;
+                check_template(&inner, &text, &origins)?;
+                check_markup(&inner, &text, &origins)?;
+                content.push(text);
             }
             _ => panic!("Unexpected token inside dialog: {:?}", pair.as_rule()),
         }
     }
+    let id = context.declare_line_id(line_id, &join_parts(content.iter().map(String::as_str)))?;
     let content = content
         .into_iter()
         .map(|text| text.as_str().into())
         .collect();
-    AstNode::Dialog(name, content)
+    Ok(AstNode::Dialog(name, portrait, content, id, tags))
+}
+
+fn parse_tags(tags: Pair<'_, Rule>) -> Result<Vec<(Identifier, Option<Text>)>, DialogError> {
+    assert_eq!(tags.as_rule(), Rule::line_tags);
+    tags.into_inner().map(parse_tag).collect()
+}
+
+fn parse_tag(tag: Pair<'_, Rule>) -> Result<(Identifier, Option<Text>), DialogError> {
+    if tag.as_rule() == Rule::bad_tag {
+        let kind = ErrorKind::BadTag(tag.as_str().to_owned());
+        return Err(DialogError::at(kind, tag.as_span()));
+    }
+    assert_eq!(tag.as_rule(), Rule::line_tag);
+    let mut inner = tag.into_inner();
+    let key = inner.next().unwrap().into();
+    let value = inner.next().map(|value| value.as_str().into());
+    Ok((key, value))
+}
+
+/// Replaces escape sequences of string with characters they stand for.
+/// Also returns source offset (inside of `inner`) of every decoded byte and of the end.
+fn decode_escapes(inner: &Pair<'_, Rule>) -> Result<(String, Vec<usize>), DialogError> {
+    let source = inner.as_str();
+    let mut text = String::with_capacity(source.len());
+    let mut origins = Vec::with_capacity(source.len() + 1);
+    let mut chars = source.char_indices().peekable();
+    while let Some((position, ch)) = chars.next() {
+        let ch = if ch == '\\' {
+            let (_, escaped) = chars.next().expect("Grammar allows only complete escapes");
+            let mut end = position + 1 + escaped.len_utf8();
+            let decoded = match escaped {
+                '"' | '\\' | '/' => Some(escaped),
+                'b' => Some('\u{8}'),
+                'f' => Some('\u{c}'),
+                'n' => Some('\n'),
+                'r' => Some('\r'),
+                't' => Some('\t'),
+                'u' => {
+                    let digits = source[end..]
+                        .bytes()
+                        .take(4)
+                        .take_while(u8::is_ascii_hexdigit)
+                        .count();
+                    let code = &source[end..end + digits];
+                    end += digits;
+                    (digits == 4)
+                        .then(|| u32::from_str_radix(code, 16).unwrap())
+                        .and_then(char::from_u32)
+                }
+                _ => None,
+            };
+            let Some(decoded) = decoded else {
+                let span = inner.as_span().get(position..end).unwrap();
+                return Err(DialogError::at(
+                    ErrorKind::BadEscape(source[position..end].into()),
+                    span,
+                ));
+            };
+            while chars.next_if(|(next, _)| *next < end).is_some() {}
+            decoded
+        } else {
+            ch
+        };
+        text.push(ch);
+        origins.resize(text.len(), position);
+    }
+    origins.push(source.len());
+    Ok((text, origins))
+}
+
+fn check_template(
+    inner: &Pair<'_, Rule>,
+    text: &str,
+    origins: &[usize],
+) -> Result<(), DialogError> {
+    parse_template(text).map(|_| ()).map_err(|err| {
+        let range = origins[err.range.start]..origins[err.range.end];
+        let span = inner.as_span().get(range).unwrap();
+        DialogError::at(ErrorKind::BadInterpolation(err.message.into()), span)
+    })
 }
 
-fn parse_command(command: Pair<'_, Rule>, context: &mut ParserContext) -> AstNode {
+fn check_markup(inner: &Pair<'_, Rule>, text: &str, origins: &[usize]) -> Result<(), DialogError> {
+    parse_markup(text).map(|_| ()).map_err(|err| {
+        let range = origins[err.range.start]..origins[err.range.end];
+        let span = inner.as_span().get(range).unwrap();
+        DialogError::at(ErrorKind::BadMarkup(err.message), span)
+    })
+}
+
+fn parse_command(
+    command: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<AstNode, DialogError> {
     let command = command.into_inner().next().unwrap();
     let command = match command.as_rule() {
         Rule::end_command => Command::End,
         Rule::jump_command => {
-            let jump_to = command.into_inner().next().unwrap().into();
-            context.demand_label(&jump_to);
+            let target = command.into_inner().next().unwrap();
+            let span = target.as_span();
+            let jump_to = target.into();
+            context.demand_label(&jump_to, span);
             Command::Jump(jump_to)
         }
+        Rule::call_command => {
+            let target = command.into_inner().next().unwrap();
+            let span = target.as_span();
+            let call = target.into();
+            context.demand_call(&call, span);
+            Command::Call(call)
+        }
+        Rule::return_command => {
+            context.declare_return(command.as_span());
+            Command::Return
+        }
         Rule::choice_command => {
             let mut inner = command.into_inner();
             let var: Identifier = inner.next().unwrap().into();
-            let choice = inner.next().unwrap().into();
-            context.demand_choice(&choice);
-            Command::Choice(var, choice)
+            let choice = inner.next().unwrap();
+            let span = choice.as_span();
+            let choice = choice.into();
+            context.demand_choice(&choice, span);
+            let tags = inner
+                .next()
+                .map(parse_tags)
+                .transpose()?
+                .unwrap_or_default();
+            Command::Choice(var, choice, tags)
         }
         Rule::trigger_command => {
-            let trigger_what = command.into_inner().next().unwrap().into();
+            let mut inner = command.into_inner();
+            let trigger_what = inner.next().unwrap().into();
             context.declare_invocation(&trigger_what);
-            Command::Trigger(trigger_what)
+            let mut arguments = Vec::new();
+            let mut result = None;
+            for pair in inner {
+                let argument = match pair.as_rule() {
+                    Rule::trigger_result => {
+                        result = Some(pair.into_inner().next().unwrap().into());
+                        continue;
+                    }
+                    Rule::expression => parse_expression(pair, context)?,
+                    Rule::name => Expression::Variable(Variable::String(pair.as_str().into())),
+                    _ => Expression::Variable(parse_variable(pair)?),
+                };
+                arguments.push(argument);
+            }
+            Command::Trigger(trigger_what, arguments, result)
+        }
+        Rule::wait_command => {
+            let literal = command.into_inner().next().unwrap();
+            let span = literal.as_span();
+            let seconds = match parse_variable(literal)? {
+                Variable::Int(seconds) => seconds as f32,
+                Variable::Float(seconds) => seconds as f32,
+                _ => unreachable!("Grammar allows only numbers"),
+            };
+            if seconds < 0.0 || !seconds.is_finite() {
+                return Err(DialogError::at(
+                    ErrorKind::BadLiteral(span.as_str().into()),
+                    span,
+                ));
+            }
+            Command::Wait(seconds)
+        }
+        Rule::set_command | Rule::add_command | Rule::sub_command => {
+            let rule = command.as_rule();
+            let mut inner = command.into_inner();
+            let var: Identifier = inner.next().unwrap().into();
+            let value = parse_expression(inner.next().unwrap(), context)?;
+            match rule {
+                Rule::set_command => Command::Set(var, value),
+                Rule::add_command => Command::Add(var, value),
+                _ => Command::Sub(var, value),
+            }
         }
         _ => panic!("Unexpected token inside a command: {:?}", command.as_rule()),
     };
-    AstNode::Command(command)
+    Ok(AstNode::Command(command))
 }
 
-fn parse_variable(var: Pair<'_, Rule>) -> Variable {
-    match var.as_rule() {
+fn parse_variable(var: Pair<'_, Rule>) -> Result<Variable, DialogError> {
+    let var = match var.as_rule() {
         Rule::boolean => {
             let value: bool = var.as_str().parse().unwrap();
             Variable::Boolean(value)
         }
         Rule::name => Variable::Global(var.into()),
-        Rule::string => {
-            let inner = var.into_inner().next().unwrap();
-            Variable::String(inner.as_str().into())
-        }
-        Rule::number => {
-            let number: i32 = var.as_str().parse().unwrap();
-            Variable::Int(number)
+        Rule::string => Variable::String(var.try_into()?),
+        Rule::int | Rule::hex_int | Rule::float => {
+            let bad_literal =
+                || DialogError::at(ErrorKind::BadLiteral(var.as_str().into()), var.as_span());
+            let literal = var.as_str();
+            match var.as_rule() {
+                Rule::int => Variable::Int(literal.parse().map_err(|_| bad_literal())?),
+                Rule::hex_int => {
+                    let (sign, digits) = match literal.strip_prefix('-') {
+                        Some(digits) => ("-", digits),
+                        None => ("", literal),
+                    };
+                    let digits = format!("{sign}{}", &digits[2..]);
+                    Variable::Int(i32::from_str_radix(&digits, 16).map_err(|_| bad_literal())?)
+                }
+                _ => {
+                    let number: f64 = literal.parse().map_err(|_| bad_literal())?;
+                    if !number.is_finite() {
+                        return Err(bad_literal());
+                    }
+                    Variable::Float(number)
+                }
+            }
         }
         _ => panic!("Unexpected token as variable: {:?}", var.as_rule()),
+    };
+    Ok(var)
+}
+
+fn parse_expression(
+    expression: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<Expression, DialogError> {
+    parse_typed_expression(expression, context).map(|(expr, _)| expr)
+}
+
+/// Builds expression tree while checking types of everything known at compile time.
+fn parse_typed_expression(
+    expression: Pair<'_, Rule>,
+    context: &mut ParserContext,
+) -> Result<(Expression, ValueType), DialogError> {
+    assert_eq!(expression.as_rule(), Rule::expression);
+    let span = expression.as_span();
+    let (expr, value_type) = PRATT_PARSER
+        .map_primary(|primary| match primary.as_rule() {
+            Rule::expression => parse_typed_expression(primary, context),
+            Rule::random_call => {
+                let span = primary.as_span();
+                let mut inner = primary.into_inner();
+                let (min, min_type) = parse_typed_expression(inner.next().unwrap(), context)?;
+                let (max, max_type) = parse_typed_expression(inner.next().unwrap(), context)?;
+                let value_type =
+                    ValueType::numeric_result(min_type, max_type).ok_or_else(|| {
+                        let message =
+                            format!("`random` can't be applied to {min_type} and {max_type}");
+                        DialogError::at(ErrorKind::TypeMismatch(message), span)
+                    })?;
+                Ok((Expression::Random(Box::new(min), Box::new(max)), value_type))
+            }
+            Rule::visits_call | Rule::visited_call => {
+                let rule = primary.as_rule();
+                let label = primary.into_inner().next().unwrap();
+                let span = label.as_span();
+                let label = label.into();
+                context.demand_label(&label, span);
+                let visits = Expression::Visits(label);
+                Ok(match rule {
+                    Rule::visits_call => (visits, ValueType::Int),
+                    _ => {
+                        let never = Expression::Variable(Variable::Int(0));
+                        let visited = Expression::Binary(
+                            Box::new(visits),
+                            BinaryOperation::Greater,
+                            Box::new(never),
+                        );
+                        (visited, ValueType::Boolean)
+                    }
+                })
+            }
+            _ => {
+                let var = parse_variable(primary)?;
+                let value_type = ValueType::of(&var);
+                Ok((Expression::Variable(var), value_type))
+            }
+        })
+        .map_prefix(|op, rhs| {
+            let (rhs, rhs_type) = rhs?;
+            let operation = match op.as_rule() {
+                Rule::op_not => UnaryOperation::Not,
+                Rule::op_neg => UnaryOperation::Negate,
+                _ => panic!("Unexpected prefix operation: {:?}", op.as_rule()),
+            };
+            let value_type = operation.result_type(rhs_type).ok_or_else(|| {
+                let message = format!("`{}` can't be applied to {rhs_type}", op.as_str());
+                DialogError::at(ErrorKind::TypeMismatch(message), op.as_span())
+            })?;
+            Ok((Expression::Unary(operation, Box::new(rhs)), value_type))
+        })
+        .map_infix(|lhs, op, rhs| {
+            let (lhs, lhs_type) = lhs?;
+            let (rhs, rhs_type) = rhs?;
+            let operation = match op.as_rule() {
+                Rule::op_or => BinaryOperation::Or,
+                Rule::op_and => BinaryOperation::And,
+                Rule::op_eq => BinaryOperation::Equal,
+                Rule::op_neq => BinaryOperation::NotEqual,
+                Rule::op_lt => BinaryOperation::Less,
+                Rule::op_le => BinaryOperation::LessEqual,
+                Rule::op_gt => BinaryOperation::Greater,
+                Rule::op_ge => BinaryOperation::GreaterEqual,
+                Rule::op_add => BinaryOperation::Add,
+                Rule::op_sub => BinaryOperation::Sub,
+                Rule::op_mul => BinaryOperation::Mul,
+                Rule::op_div => BinaryOperation::Div,
+                Rule::op_rem => BinaryOperation::Rem,
+                _ => panic!("Unexpected infix operation: {:?}", op.as_rule()),
+            };
+            let value_type = operation.result_type(lhs_type, rhs_type).ok_or_else(|| {
+                let message = format!(
+                    "`{}` can't be applied to {lhs_type} and {rhs_type}",
+                    op.as_str()
+                );
+                DialogError::at(ErrorKind::TypeMismatch(message), op.as_span())
+            })?;
+            let expr = Expression::Binary(Box::new(lhs), operation, Box::new(rhs));
+            Ok((expr, value_type))
+        })
+        .parse(expression.into_inner())?;
+    // Deeper expression couldn't be loaded back from the compiled script
+    if expr.depth() > MAX_EXPRESSION_DEPTH {
+        return Err(DialogError::at(ErrorKind::ExpressionTooDeep, span));
+    }
+    Ok((expr, value_type))
+}
+
+/// Type of expression known at compile time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ValueType {
+    Boolean,
+    Int,
+    Float,
+    String,
+    /// Value of global variable is known only at runtime.
+    Unknown,
+}
+
+impl ValueType {
+    fn of(var: &Variable) -> Self {
+        match var {
+            Variable::Global(_) => Self::Unknown,
+            Variable::Boolean(_) => Self::Boolean,
+            Variable::String(_) => Self::String,
+            Variable::Int(_) => Self::Int,
+            Variable::Float(_) => Self::Float,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Self::Int | Self::Float | Self::Unknown)
+    }
+
+    /// Int and float mixed together give float.
+    fn numeric_result(lhs: Self, rhs: Self) -> Option<Self> {
+        match (lhs, rhs) {
+            _ if !lhs.is_numeric() || !rhs.is_numeric() => None,
+            (Self::Int, Self::Int) => Some(Self::Int),
+            (Self::Float, _) | (_, Self::Float) => Some(Self::Float),
+            _ => Some(Self::Unknown),
+        }
+    }
+}
+
+impl std::fmt::Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ValueType::Boolean => "boolean",
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::String => "string",
+            ValueType::Unknown => "variable",
+        })
     }
 }
 
-fn parse_logic_op(op: Pair<'_, Rule>) -> LogicOperation {
-    match op.as_str() {
-        "==" => LogicOperation::Equal,
-        "!=" => LogicOperation::NotEqual,
-        _ => panic!("Unexpected logic operation: {}", op.as_str()),
+impl UnaryOperation {
+    fn result_type(self, operand: ValueType) -> Option<ValueType> {
+        match self {
+            UnaryOperation::Not => Some(ValueType::Boolean),
+            UnaryOperation::Negate => operand.is_numeric().then_some(operand),
+        }
+    }
+}
+
+impl BinaryOperation {
+    fn result_type(self, lhs: ValueType, rhs: ValueType) -> Option<ValueType> {
+        use ValueType::*;
+        match self {
+            BinaryOperation::Or | BinaryOperation::And => Some(Boolean),
+            // Comparing values of different types is always false, so it is surely a mistake
+            BinaryOperation::Equal | BinaryOperation::NotEqual => {
+                let numbers = lhs.is_numeric() && rhs.is_numeric();
+                (lhs == rhs || lhs == Unknown || rhs == Unknown || numbers).then_some(Boolean)
+            }
+            BinaryOperation::Less
+            | BinaryOperation::LessEqual
+            | BinaryOperation::Greater
+            | BinaryOperation::GreaterEqual => {
+                (lhs.is_numeric() && rhs.is_numeric()).then_some(Boolean)
+            }
+            // Addition also concatenates strings
+            BinaryOperation::Add => match (lhs, rhs) {
+                (String, String) => Some(String),
+                (String, Unknown) | (Unknown, String) => Some(Unknown),
+                _ => ValueType::numeric_result(lhs, rhs),
+            },
+            BinaryOperation::Sub
+            | BinaryOperation::Mul
+            | BinaryOperation::Div
+            | BinaryOperation::Rem => ValueType::numeric_result(lhs, rhs),
+        }
     }
 }
 
@@ -329,11 +1240,11 @@ impl From<Pair<'_, Rule>> for Identifier {
     fn from(value: Pair<'_, Rule>) -> Self {
         let name = match value.as_rule() {
             Rule::label => value.into_inner().next().unwrap(),
-            Rule::name => value,
+            Rule::name | Rule::qualified_name => value,
             _ => panic!("Unexpected type for identifier: {:?}", value.as_rule()),
         };
         assert!(
-            matches!(name.as_rule(), Rule::name),
+            matches!(name.as_rule(), Rule::name | Rule::qualified_name),
             "Label don't have a name! Please check grammar file!"
         );
         Self(name.as_str().into())
@@ -358,20 +1269,28 @@ impl Identifier {
     pub(crate) fn as_rc(&self) -> &Rc<str> {
         &self.0
     }
+
+    /// Whether it refers to another file, like `town.greeting`.
+    pub(crate) fn is_qualified(&self) -> bool {
+        self.0.contains('.')
+    }
 }
 
-impl From<Pair<'_, Rule>> for Text {
-    fn from(string: Pair<'_, Rule>) -> Self {
+impl TryFrom<Pair<'_, Rule>> for Text {
+    type Error = DialogError;
+
+    fn try_from(string: Pair<'_, Rule>) -> Result<Self, Self::Error> {
         assert!(
             matches!(string.as_rule(), Rule::string),
-            "Expected label pair as argument!"
+            "Expected string pair as argument!"
         );
         let inner = string.into_inner().next().unwrap();
         assert!(
             matches!(inner.as_rule(), Rule::inner),
-            "Label don't have a name! Please check grammar file!"
+            "String don't have a content! Please check grammar file!"
         );
-        Self(inner.as_str().into())
+        let (text, _) = decode_escapes(&inner)?;
+        Ok(Self(text.into()))
     }
 }
 
@@ -401,7 +1320,10 @@ mod test {
 
     use pest::Parser;
 
-    use crate::grammar::{DirectScriptParser, Identifier, Rule, parse_to_ast};
+    use crate::{
+        error::ErrorKind,
+        grammar::{AstNode, Command, DirectScriptParser, Identifier, Rule, Text, parse_to_ast},
+    };
 
     #[test]
     fn is_the_same() {
@@ -426,4 +1348,425 @@ mod test {
             .unwrap();
         println!("Pairs: {pairs:#?}");
     }
+
+    #[test]
+    fn duplicated_label_is_reported() {
+        let source = "start:\n    end\n\nstart:\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicatedLabel("start".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (4, 1));
+        assert_eq!(location.snippet, "start:");
+    }
+
+    #[test]
+    fn duplicated_inline_label_is_reported() {
+        let source = "start:\n    again:\n    again:\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicatedLabel("again".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (3, 5));
+    }
+
+    #[test]
+    fn duplicated_choice_is_reported() {
+        let source = "define_choice yes_no that\n    yes -> \"Yes\"\nend_choice\n\
+            define_choice yes_no that\n    no -> \"No\"\nend_choice\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicatedChoice("yes_no".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (4, 15));
+    }
+
+    #[test]
+    fn duplicated_option_is_reported() {
+        let source =
+            "define_choice yes_no that\n    yes -> \"Yes\"\n    yes -> \"Sure\"\nend_choice\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicatedOption("yes".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (3, 5));
+
+        let source = "start:\n    menu answer\n        yes -> \"Yes\"\n            \"Fine\"\n        \
+            no -> \"No\"\n        yes -> \"Sure\"\n    endmenu\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicatedOption("yes".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (6, 9));
+    }
+
+    #[test]
+    fn line_ids_are_stable() {
+        let ids = |source: &str| -> Vec<String> {
+            let ast = parse_to_ast(source).unwrap();
+            let AstNode::LabelBlock(_, nodes) = &ast[0] else {
+                panic!("Expected label block");
+            };
+            nodes
+                .iter()
+                .filter_map(|node| match node {
+                    AstNode::Dialog(_, _, _, id, _) => Some(id.as_str().to_owned()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let first = ids("start:\n    \"Hi!\"\n    \"Bye.\"\n    \"Hi!\"\n");
+        let second = ids("start:\n    \"Oh.\"\n    \"Hi!\"\n    \"Bye.\" #id:farewell\n");
+        assert!(first[0].starts_with("start."));
+        // The same text gets the same id wherever it is
+        assert_eq!(first[0], second[1]);
+        assert_eq!(first[2], format!("{}:2", first[0]));
+        assert_eq!(second[2], "farewell");
+    }
+
+    #[test]
+    fn line_tags_are_parsed() {
+        let source = "start:\n    who -> \"Hi!\" # id:hi @voice:who_001 @closeup\n    \
+            \"Hm.\" # todo: @check this\n    choice answer yes_no #@music:tense\n    \
+            menu answer # @timer:5\n        yes -> \"Yes\"\n    endmenu\n\n\
+            define_character who that\n    name -> \"Who\"\nend_character\n\n\
+            define_choice yes_no that\n    yes -> \"Yes\"\nend_choice\n";
+        let ast = parse_to_ast(source).unwrap();
+        let AstNode::LabelBlock(_, nodes) = &ast[0] else {
+            panic!("Expected label block");
+        };
+        let tags = |tags: &[(Identifier, Option<Text>)]| -> Vec<(String, Option<String>)> {
+            tags.iter()
+                .map(|(key, value)| {
+                    let value = value.as_ref().map(|value| value.as_str().to_owned());
+                    (key.as_str().to_owned(), value)
+                })
+                .collect()
+        };
+        let AstNode::Dialog(_, _, _, id, line_tags) = &nodes[0] else {
+            panic!("Expected dialog");
+        };
+        assert_eq!(id.as_str(), "hi");
+        assert_eq!(
+            tags(line_tags),
+            [
+                ("voice".to_owned(), Some("who_001".to_owned())),
+                ("closeup".to_owned(), None)
+            ]
+        );
+        // Comment that doesn't start with a tag or an id is a plain one
+        let AstNode::Dialog(_, _, _, _, line_tags) = &nodes[1] else {
+            panic!("Expected dialog");
+        };
+        assert!(line_tags.is_empty());
+        let AstNode::Command(Command::Choice(_, _, line_tags)) = &nodes[2] else {
+            panic!("Expected choice");
+        };
+        assert_eq!(
+            tags(line_tags),
+            [("music".to_owned(), Some("tense".to_owned()))]
+        );
+        let AstNode::Menu(_, _, line_tags, _) = &nodes[3] else {
+            panic!("Expected menu");
+        };
+        assert_eq!(
+            tags(line_tags),
+            [("timer".to_owned(), Some("5".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn malformed_tags_are_reported() {
+        let tag_error = |line: &str| {
+            let source = format!("start:\n    {line}\n    end\n");
+            let error = parse_to_ast(&source).unwrap_err();
+            let location = error.location().unwrap();
+            (error.kind().clone(), location.line, location.column)
+        };
+        // Explicit id isn't lost because of a word after it
+        assert_eq!(
+            tag_error("\"B\" # id:b note"),
+            (ErrorKind::BadTag("note".into()), 2, 16)
+        );
+        assert_eq!(
+            tag_error("\"B\" # @voice: x01"),
+            (ErrorKind::BadTag("@voice:".into()), 2, 11)
+        );
+        assert_eq!(
+            tag_error("\"B\" # @voice:x01 id:b-c"),
+            (ErrorKind::BadTag("id:b-c".into()), 2, 22)
+        );
+        // Choices have no ids
+        let source = "start:\n    choice answer yes_no # id:ask\n    end\n\n\
+            define_choice yes_no that\n    yes -> \"Yes\"\nend_choice\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::BadTag("id:ask".into()));
+    }
+
+    #[test]
+    fn duplicated_line_id_is_reported() {
+        let source = "start:\n    \"Hi!\" #id:hello\n    \"Hello!\" #id:hello\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicatedLineId("hello".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (3, 18));
+    }
+
+    #[test]
+    fn undefined_label_is_reported() {
+        let source = "start:\n    \"Hi!\"\n    jump nowhere\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::UndefinedLabel("nowhere".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (3, 10));
+        assert_eq!(location.snippet, "    jump nowhere");
+    }
+
+    #[test]
+    fn undefined_visited_label_is_reported() {
+        let source = "start:\n    if visited(nowhere) then\n        end\n    endif\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::UndefinedLabel("nowhere".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 16));
+    }
+
+    #[test]
+    fn return_outside_call_is_reported() {
+        let source = "start:\n    call helper\n    end\n\nhelper:\n    \"Hi!\"\n    return\n\n\
+            alone:\n    \"Bye!\"\n    return\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ReturnOutsideCall("alone".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (11, 5));
+    }
+
+    #[test]
+    fn return_after_inline_label_belongs_to_block() {
+        let source = "start:\n    call helper\n    end\n\n\
+            helper:\n    \"Hi\"\n    again:\n    \"Again\"\n    return\n";
+        assert!(parse_to_ast(source).is_ok());
+        // Calling only the inline label is enough too
+        let source = "start:\n    call again\n    end\n\n\
+            helper:\n    \"Hi\"\n    again:\n    \"Again\"\n    return\n";
+        assert!(parse_to_ast(source).is_ok());
+        // Label below the return doesn't run into it
+        let source = "start:\n    call later\n    end\n\n\
+            helper:\n    \"Hi\"\n    return\n    later:\n    \"Later\"\n    return\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ReturnOutsideCall("helper".into()));
+        assert_eq!(error.location().unwrap().line, 7);
+    }
+
+    #[test]
+    fn undefined_choice_is_reported() {
+        let source = "start:\n    choice answer nothing\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::UndefinedChoice("nothing".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 19));
+    }
+
+    #[test]
+    fn undefined_speaker_is_reported() {
+        let source = "define_character kel that\n    name -> \"KEL\"\nend_character\n\n\
+            start:\n    kel -> \"Hey!\"\n    kell -> \"Hi!\"\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::UndefinedCharacter("kell".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (7, 5));
+    }
+
+    #[test]
+    fn bad_character_is_reported() {
+        let source = "define_character kel that\n    name -> \"KEL\"\nend_character\n\
+            define_character kel that\n    name -> \"Kel\"\nend_character\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicatedCharacter("kel".into()));
+        assert_eq!(error.location().unwrap().line, 4);
+
+        let source =
+            "define_character kel that\n    name -> \"KEL\"\n    name -> \"Kel\"\nend_character\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::BadCharacter("`name` is set more than once".into())
+        );
+        assert_eq!(error.location().unwrap().line, 3);
+
+        let source = "define_character kel that\n    color -> \"orange!\"\nend_character\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadCharacter(_)));
+        assert_eq!(error.location().unwrap().column, 14);
+
+        // Default portrait has to be declared too
+        let source = "define_character kel that\n    portrait -> smug\nend_character\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::UndefinedPortrait("kel".into(), "smug".into())
+        );
+    }
+
+    #[test]
+    fn undefined_portrait_is_reported() {
+        let source = "define_character aubrey that\n    name -> \"AUBREY\"\nend_character\n\
+            define_portraits aubrey neutral angry\n\nstart:\n    aubrey[angry] -> \"Hey!\"\n    aubrey[happy] -> \"Hi!\"\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::UndefinedPortrait("aubrey".into(), "happy".into())
+        );
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (8, 11));
+    }
+
+    #[test]
+    fn bad_literal_is_reported() {
+        let source = "start:\n    if gold == 99999999999 then\n        end\n    endif\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::BadLiteral("99999999999".into()));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 16));
+        assert_eq!(location.range.1 - location.range.0, 11);
+    }
+
+    #[test]
+    fn bad_random_is_reported() {
+        let source =
+            "start:\n    random\n        \"A\"\n    or 0\n        \"B\"\n    endrandom\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::BadLiteral("0".into()));
+        assert_eq!(error.location().unwrap().line, 4);
+
+        let source = "start:\n    set roll = random(\"one\", 6)\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::TypeMismatch(_)));
+        assert_eq!(error.location().unwrap().column, 16);
+
+        // Without parentheses it's just a variable
+        let source = "start:\n    set roll = random + 1\n    end\n";
+        parse_to_ast(source).unwrap();
+    }
+
+    #[test]
+    fn negative_wait_is_reported() {
+        let source = "start:\n    wait 1.5\n    wait 2\n    end\n";
+        parse_to_ast(source).unwrap();
+
+        let source = "start:\n    wait -0.5\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::BadLiteral("-0.5".into()));
+        assert_eq!(error.location().unwrap().column, 10);
+    }
+
+    #[test]
+    fn syntax_error_has_location() {
+        let source = "start:\n    who => \"Hi!\"\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Syntax(_)));
+        let location = error.location().unwrap();
+        assert_eq!(location.line, 2);
+        assert!(error.to_string().contains("who => \"Hi!\""));
+    }
+
+    #[test]
+    fn type_mismatch_is_reported() {
+        let source = "start:\n    if \"two\" < 3 then\n        end\n    endif\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::TypeMismatch(_)));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 14));
+
+        let source = "start:\n    set gold = true * (2 + 1)\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::TypeMismatch(_)));
+        assert_eq!(error.location().unwrap().column, 21);
+
+        let source = "start:\n    if name == 2 or -\"text\" then\n        end\n    endif\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::TypeMismatch(_)));
+        assert_eq!(error.location().unwrap().column, 21);
+    }
+
+    #[test]
+    fn keywords_are_not_part_of_names() {
+        let source =
+            "start:\n    if not nothing and order or android then\n        end\n    endif\n";
+        parse_to_ast(source).unwrap();
+    }
+
+    #[test]
+    fn out_of_range_literals_are_reported() {
+        for literal in ["2147483648", "-2147483649", "0x80000000", "1e3"] {
+            let source = format!("start:\n    set x = {literal}\n    end\n");
+            let error = parse_to_ast(&source).unwrap_err();
+            if literal == "1e3" {
+                // Exponent notation isn't supported
+                assert!(matches!(error.kind(), ErrorKind::Syntax(_)));
+            } else {
+                assert_eq!(error.kind(), &ErrorKind::BadLiteral(literal.into()));
+            }
+        }
+        let source = "start:\n    set x = 1.5 + \"clams\"\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::TypeMismatch(_)));
+    }
+
+    #[test]
+    fn bad_interpolation_is_reported() {
+        let source = "start:\n    who -> \"Fine.\", \"You have {1gold} clams\"\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadInterpolation(_)));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 31));
+        assert_eq!(location.range.1 - location.range.0, 7);
+    }
+
+    #[test]
+    fn bad_markup_is_reported() {
+        let source = "start:\n    who -> \"Fine.\", \"Run [shake]now!\"\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadMarkup(_)));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 26));
+        assert_eq!(location.range.1 - location.range.0, 7);
+    }
+
+    #[test]
+    fn escapes_are_decoded() {
+        let source =
+            "start:\n    \"Tab\\there, \\\"quote\\\"\\\\\", \"\\u00e9t\\u00E9\"\n    end\n";
+        let ast = parse_to_ast(source).unwrap();
+        let AstNode::LabelBlock(_, nodes) = &ast[0] else {
+            panic!("Expected label block");
+        };
+        let AstNode::Dialog(_, _, texts, ..) = &nodes[0] else {
+            panic!("Expected dialog");
+        };
+        assert_eq!(texts[0].as_str(), "Tab\there, \"quote\"\\ ");
+        assert_eq!(texts[1].as_str(), "été");
+    }
+
+    #[test]
+    fn bad_escape_is_reported() {
+        let cases = [
+            ("\"Fine.\", \"Hm\\q...\"", 17, 2),
+            ("\"Fine.\", \"Hm\\u12\"", 17, 4),
+            ("\"Fine.\", \"Hm\\uD800\"", 17, 6),
+        ];
+        for (dialog, column, length) in cases {
+            let source = format!("start:\n    {dialog}\n    end\n");
+            let error = parse_to_ast(&source).unwrap_err();
+            assert!(matches!(error.kind(), ErrorKind::BadEscape(_)), "{dialog}");
+            let location = error.location().unwrap();
+            assert_eq!((location.line, location.column), (2, column), "{dialog}");
+            assert_eq!(location.range.1 - location.range.0, length, "{dialog}");
+        }
+    }
+
+    #[test]
+    fn errors_point_into_escaped_text() {
+        let source = "start:\n    \"\\u00e9\\t[blink]\"\n    end\n";
+        let error = parse_to_ast(source).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadMarkup(_)));
+        let location = error.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 14));
+        assert_eq!(location.range.1 - location.range.0, 7);
+    }
 }
@@ -1,77 +1,514 @@
 use std::{
-    collections::HashMap,
-    num::{NonZeroU16, NonZeroU32},
-    path::Path,
+    cell::Cell, cmp::Ordering, collections::HashMap, fmt::Display, num::NonZeroU32, path::Path,
     rc::Rc,
 };
 
 use crate::{
-    grammar::{AstNode, Identifier, Text},
-    utils::UniquePush,
+    binary::{self, Decode, Encode, Reader},
+    error::{DialogError, ErrorKind},
+    grammar::{AstNode, Character, Identifier, MAX_EXPRESSION_DEPTH, Text},
+    locale::Translation,
+    markup::{StyledSpan, join_parts, parse_markup},
+    random::Rng,
+    template::{Piece, has_placeholders, parse_template},
+    utils::{UniquePush, fnv1a},
 };
 
-type ChoiceVariants = Rc<[(Identifier, Text)]>;
+/// name | text | whether it can be picked
+type ChoiceVariants = Vec<(Identifier, Text, bool)>;
+type ChoiceOptions = Box<[(Identifier, Text, OptionCondition)]>;
+/// Tags written after the line, like `# @voice:omori_042`, flags have no value.
+pub type Tags = Rc<[(Identifier, Option<Text>)]>;
+/// text without markup | stops | styles
+type TextEntry = (Text, Box<[Stop]>, Box<[StyledSpan]>);
 
 #[derive(Debug)]
 pub struct DirectScript {
     code: Box<[Command]>,
     strings: Box<[Identifier]>,
-    texts: Box<[(Text, [Option<NonZeroU16>; 12])]>,
+    /// Lines in the current language, compiled from `lines` or their translation
+    texts: Box<[TextEntry]>,
+    /// Id and source text of every line
+    lines: Box<[(Identifier, Text)]>,
     labels: Box<[(Identifier, usize)]>,
-    choices: Box<[(Identifier, ChoiceVariants)]>,
+    choices: Box<[(Identifier, ChoiceOptions)]>,
+    /// Texts of options in the current language, in the same order as `choices`
+    option_texts: Box<[Box<[Text]>]>,
+    characters: Box<[(Identifier, Rc<Character>)]>,
     source: Option<Rc<Path>>,
 }
 
 #[derive(Debug)]
 enum Command {
-    /// who | what
-    Text(Option<NonZeroU32>, u32),
+    /// who | portrait | what | tags
+    Text(Option<NonZeroU32>, Option<NonZeroU32>, u32, Tags),
     Jump(usize),
-    Choice(u32, u32),
-    Trigger(u32),
+    Call(usize),
+    Return,
+    /// where to store | what | tags
+    Choice(u32, u32, Tags),
+    /// what | arguments | where to store the result
+    Trigger(u32, Box<[Expression]>, Option<u32>),
+    /// seconds
+    Wait(f32),
+    /// where | what
+    Set(u32, Expression),
+    Add(u32, Expression),
+    Sub(u32, Expression),
     End,
-    EvalCondition(Condition),
-    If(usize),
+    /// condition | how much to skip if it's false
+    If(Expression, usize),
+    /// variable with answer | where to go for each answer | where to go otherwise
+    Switch(u32, Box<[(u32, usize)]>, usize),
+    /// weight | where to go, for each branch
+    Random(Box<[(u32, usize)]>),
+    /// Counts that label was entered, holds name of the counter
+    Visit(u32),
     // Else is just jump
 }
 
+#[derive(Debug)]
+enum OptionCondition {
+    Always,
+    ShownIf(Expression),
+    EnabledIf(Expression),
+}
+
 #[derive(Debug)]
 enum Variable {
     Name(u32),
     Boolean(bool),
     Text(u32),
     Int(i32),
+    Float(f64),
 }
 
 #[repr(u8)]
-#[derive(Debug)]
-enum LogicOperation {
+#[derive(Clone, Copy, Debug)]
+enum UnaryOperation {
+    Not,
+    Negate,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+enum BinaryOperation {
+    Or,
+    And,
     Equal,
     NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
 }
 
 #[derive(Debug)]
-enum Condition {
+enum Expression {
     Var(Variable),
-    Expr(Variable, LogicOperation, Variable),
+    Unary(UnaryOperation, Box<Expression>),
+    Binary(Box<Expression>, BinaryOperation, Box<Expression>),
+    Random(Box<Expression>, Box<Expression>),
+    /// Name of the visit counter
+    Visits(u32),
 }
 
+type MissingFormatter = Box<dyn Fn(&str) -> String>;
+
+/// How deep `call`s can be nested, prevents endless recursion from eating memory.
+pub const MAX_CALL_DEPTH: usize = 64;
+
 pub struct DirectExecution {
     script: Rc<DirectScript>,
     code_ptr: usize,
-    last_condition: bool,
+    /// Where to continue after `return`
+    call_stack: Vec<usize>,
+    /// Choice that waits for an answer, execution doesn't go on until it's given
+    pending_choice: Option<(Identifier, ChoiceVariants, Tags)>,
+    /// Trigger that waits for its result the same way
+    pending_trigger: Option<(Identifier, Vec<Variant>, Identifier)>,
+    missing_formatter: MissingFormatter,
+    /// Seed of `rng`, the same seed replays the same random picks
+    seed: u64,
+    /// Expressions are evaluated through shared reference, so it needs a cell
+    rng: Cell<Rng>,
+    /// Text command of the last step, snapshot goes back to it to show the line again
+    shown_text: Option<usize>,
+}
+
+/// Part of execution that is saved by [`DirectExecution::snapshot`],
+/// the rest is taken from the script on restore.
+struct Snapshot {
+    /// [`DirectScript::content_hash`] of the script that was running
+    script_hash: u32,
+    code_ptr: usize,
+    call_stack: Vec<usize>,
+    /// Offered options of the pending choice, their texts are looked up again
+    pending_choice: Option<Vec<(Identifier, bool)>>,
+    /// Arguments of the pending trigger
+    pending_trigger: Option<Vec<Variant>>,
+    seed: u64,
+    /// State of the generator, it has moved since the seed
+    rng: u64,
+}
+
+/// Option of the pending choice, picked by its position or name.
+#[derive(Clone, Copy, Debug)]
+pub enum Answer<'a> {
+    Index(usize),
+    Name(&'a str),
+}
+
+impl From<usize> for Answer<'_> {
+    fn from(index: usize) -> Self {
+        Answer::Index(index)
+    }
+}
+
+impl<'a> From<&'a str> for Answer<'a> {
+    fn from(name: &'a str) -> Self {
+        Answer::Name(name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnswerError {
+    /// There is no choice waiting for an answer or trigger waiting for a result.
+    NotAsked,
+    /// Index is past the end of offered options.
+    OutOfRange(usize),
+    /// No offered option has such name, hidden ones included.
+    Unknown(String),
+    /// Option is shown, but can't be picked right now.
+    Disabled(Identifier),
+}
+
+impl Display for AnswerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnswerError::NotAsked => write!(f, "nothing is waiting for an answer"),
+            AnswerError::OutOfRange(index) => write!(f, "there is no option #{index}"),
+            AnswerError::Unknown(name) => write!(f, "there is no option `{name}`"),
+            AnswerError::Disabled(name) => write!(f, "option `{}` is disabled", name.as_str()),
+        }
+    }
+}
+
+impl std::error::Error for AnswerError {}
+
+/// Problem that stops execution, the dialog can't go on after it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuntimeError {
+    /// `call`s are nested deeper than [`MAX_CALL_DEPTH`], most likely endless recursion.
+    CallTooDeep,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::CallTooDeep => {
+                write!(f, "calls are nested deeper than {MAX_CALL_DEPTH} levels")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Place in text where its animation stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stop {
+    /// Number of characters shown before the stop.
+    pub position: usize,
+    /// Seconds after which text goes on by itself, manual stops wait for the player.
+    pub delay: Option<f32>,
+}
+
+/// Character who says the text.
+#[derive(Clone, Debug)]
+pub struct Speaker {
+    pub id: Identifier,
+    pub character: Rc<Character>,
+    /// Portrait of the line or the default one of the character.
+    pub portrait: Option<Identifier>,
 }
 
 #[derive(Debug)]
 pub enum ExecutionStep {
-    Text(Option<Identifier>, Text, Vec<usize>),
-    Choice(Identifier, ChoiceVariants),
-    Trigger(Identifier),
+    /// who | plain text | stops | styles with byte ranges | tags
+    Text(Option<Speaker>, Text, Vec<Stop>, Vec<StyledSpan>, Tags),
+    /// where to store | options that aren't hidden | tags.
+    /// Choice without an enabled option is skipped instead.
+    Choice(Identifier, ChoiceVariants, Tags),
+    /// what | arguments | variable waiting for the result
+    Trigger(Identifier, Vec<Variant>, Option<Identifier>),
+    /// Seconds to wait before the next step, nothing is shown meanwhile
+    Wait(f32),
     End,
+    /// Execution is broken, every following step gives the same error
+    Error(RuntimeError),
+}
+
+impl DirectScript {
+    /// Root file of the project the script was loaded from.
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
+    pub(crate) fn with_source(mut self, source: Rc<Path>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Shows texts of `translation` instead of the source ones, lines and options it lacks
+    /// stay in the source language. `None` brings back the source language.
+    pub fn set_translation(
+        &mut self,
+        translation: Option<&Translation>,
+    ) -> Result<(), DialogError> {
+        let translated = |id: &str| translation.and_then(|translation| translation.get(id));
+        let texts = self
+            .lines
+            .iter()
+            .map(|(id, source)| {
+                let text = translated(id.as_str()).unwrap_or(source);
+                compile_text(text.as_str()).map_err(|message| {
+                    let message = format!("line `{}`: {message}", id.as_str());
+                    DialogError::new(ErrorKind::BadTranslation(message), None)
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        self.texts = texts;
+        self.option_texts = self
+            .choices
+            .iter()
+            .map(|(choice, options)| {
+                options
+                    .iter()
+                    .map(|(name, source, _)| {
+                        let id = format!("{}.{}", choice.as_str(), name.as_str());
+                        translated(&id).unwrap_or(source).clone()
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Makes sure that every index of loaded script points inside of its table,
+    /// so execution can rely on them the same way as on the parsed ones.
+    fn check_references(&self) -> Result<(), String> {
+        let string = |index: u32| -> Result<&Identifier, String> {
+            self.strings
+                .get(index as usize)
+                .ok_or_else(|| format!("string #{index} is out of range"))
+        };
+        // Target right after the last command is fine, it ends the dialog
+        let target = |target: usize| -> Result<(), String> {
+            if target > self.code.len() {
+                return Err(format!("jump to #{target} is out of range"));
+            }
+            Ok(())
+        };
+        for (ptr, command) in self.code.iter().enumerate() {
+            match command {
+                Command::Text(who, portrait, what, _) => {
+                    if let Some(who) = who {
+                        let who = string(who.get())?;
+                        if !self.characters.iter().any(|(item, _)| item == who) {
+                            return Err(format!("character `{}` is unknown", who.as_str()));
+                        }
+                    }
+                    if let Some(portrait) = portrait {
+                        string(portrait.get())?;
+                    }
+                    if *what as usize >= self.lines.len() {
+                        return Err(format!("line #{what} is out of range"));
+                    }
+                }
+                Command::Jump(to) | Command::Call(to) => target(*to)?,
+                Command::Return | Command::End => {}
+                Command::Choice(store_to, what, _) => {
+                    string(*store_to)?;
+                    if *what as usize >= self.choices.len() {
+                        return Err(format!("choice #{what} is out of range"));
+                    }
+                }
+                Command::Trigger(what, args, store_to) => {
+                    string(*what)?;
+                    for arg in args {
+                        self.check_expression(arg)?;
+                    }
+                    if let Some(store_to) = store_to {
+                        string(*store_to)?;
+                    }
+                }
+                Command::Wait(seconds) => {
+                    if !seconds.is_finite() || *seconds < 0.0 {
+                        return Err(format!("wait of {seconds} seconds"));
+                    }
+                }
+                Command::Set(name, value)
+                | Command::Add(name, value)
+                | Command::Sub(name, value) => {
+                    string(*name)?;
+                    self.check_expression(value)?;
+                }
+                Command::If(condition, skip) => {
+                    self.check_expression(condition)?;
+                    target(ptr.checked_add(*skip).ok_or("skip is out of range")?)?;
+                }
+                Command::Switch(name, table, otherwise) => {
+                    string(*name)?;
+                    for (option, to) in table {
+                        string(*option)?;
+                        target(*to)?;
+                    }
+                    target(*otherwise)?;
+                }
+                Command::Random(branches) => {
+                    if branches.is_empty() || branches.iter().any(|(weight, _)| *weight == 0) {
+                        return Err("random block without weight".into());
+                    }
+                    for (_, to) in branches {
+                        target(*to)?;
+                    }
+                }
+                Command::Visit(counter) => _ = string(*counter)?,
+            }
+        }
+        for (_, to) in &self.labels {
+            target(*to)?;
+        }
+        for (_, options) in &self.choices {
+            for (_, _, condition) in options {
+                match condition {
+                    OptionCondition::Always => {}
+                    OptionCondition::ShownIf(condition) | OptionCondition::EnabledIf(condition) => {
+                        self.check_expression(condition)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_expression(&self, expression: &Expression) -> Result<(), String> {
+        let string = |index: &u32| {
+            if *index as usize >= self.strings.len() {
+                return Err(format!("string #{index} is out of range"));
+            }
+            Ok(())
+        };
+        match expression {
+            Expression::Var(Variable::Name(index) | Variable::Text(index)) => string(index),
+            Expression::Var(_) => Ok(()),
+            Expression::Unary(_, operand) => self.check_expression(operand),
+            Expression::Binary(lhs, _, rhs) | Expression::Random(lhs, rhs) => {
+                self.check_expression(lhs)?;
+                self.check_expression(rhs)
+            }
+            Expression::Visits(counter) => string(counter),
+        }
+    }
+
+    /// Id and source text of every line, then of every option.
+    pub(crate) fn sources(&self) -> impl Iterator<Item = (String, &Text)> {
+        let lines = self
+            .lines
+            .iter()
+            .map(|(id, text)| (id.as_str().to_owned(), text));
+        let options = self.choices.iter().flat_map(|(choice, options)| {
+            options.iter().map(move |(name, text, _)| {
+                (format!("{}.{}", choice.as_str(), name.as_str()), text)
+            })
+        });
+        lines.chain(options)
+    }
+
+    /// Compiles the script into binary form, loading it back doesn't need the parser.
+    /// Texts are stored in the source language.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        binary::SCRIPT.wrap(&self.payload())
+    }
+
+    /// Hash of everything the script does and says in the source language,
+    /// it changes whenever the script does, but not with translation.
+    pub fn content_hash(&self) -> u32 {
+        fnv1a(&self.payload())
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.code.encode(&mut payload);
+        self.strings.encode(&mut payload);
+        self.lines.encode(&mut payload);
+        self.labels.encode(&mut payload);
+        self.choices.encode(&mut payload);
+        self.characters.encode(&mut payload);
+        payload
+    }
+
+    /// Loads the script made by [`DirectScript::to_bytes`], files of other format versions
+    /// and corrupted ones are rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DialogError> {
+        let mut script = binary::SCRIPT.unwrap(bytes, |input| {
+            Ok(DirectScript {
+                code: Decode::decode(input)?,
+                strings: Decode::decode(input)?,
+                texts: Box::new([]),
+                lines: Decode::decode(input)?,
+                labels: Decode::decode(input)?,
+                choices: Decode::decode(input)?,
+                option_texts: Box::new([]),
+                characters: Decode::decode(input)?,
+                source: None,
+            })
+        })?;
+        script
+            .check_references()
+            .map_err(|message| DialogError::new(ErrorKind::BadCompiledScript(message), None))?;
+        script.set_translation(None).map_err(|err| {
+            let kind = ErrorKind::BadCompiledScript(err.kind().to_string());
+            DialogError::new(kind, None)
+        })?;
+        Ok(script)
+    }
+}
+
+/// Strips markup from the line, leaving its stops and styles.
+/// The line always ends with a stop, even if it isn't written.
+fn compile_text(source: &str) -> Result<TextEntry, String> {
+    parse_template(source).map_err(|err| err.message.to_owned())?;
+    let (plain, styles, pauses) = parse_markup(source).map_err(|err| err.message)?;
+    let mut stops: Vec<_> = pauses
+        .into_iter()
+        .map(|(position, delay)| Stop {
+            position: plain[..position].chars().count(),
+            delay,
+        })
+        .collect();
+    let length = plain.chars().count();
+    if !stops
+        .last()
+        .is_some_and(|stop| stop.position == length && stop.delay.is_none())
+    {
+        stops.push(Stop {
+            position: length,
+            delay: None,
+        });
+    }
+    Ok((plain.as_str().into(), stops.into(), styles.into()))
 }
 
 impl DirectExecution {
     pub fn start(script: &Rc<DirectScript>, label: &str) -> Option<DirectExecution> {
+        let seed = Rng::seed_from_time();
         if let Some((_, code_ptr)) = script
             .labels
             .iter()
@@ -80,18 +517,184 @@ impl DirectExecution {
             Some(DirectExecution {
                 script: script.clone(),
                 code_ptr: *code_ptr,
-                last_condition: false,
+                call_stack: Vec::new(),
+                pending_choice: None,
+                pending_trigger: None,
+                missing_formatter: Box::new(|name| format!("{{{name}}}")),
+                seed,
+                rng: Cell::new(Rng::new(seed)),
+                shown_text: None,
             })
         } else {
             None
         }
     }
+
+    /// Sets what is shown in place of `{name}` if there is no such variable.
+    /// By default placeholder is left as is.
+    pub fn set_missing_formatter(&mut self, formatter: impl Fn(&str) -> String + 'static) {
+        self.missing_formatter = Box::new(formatter);
+    }
+
+    /// Seed of random picks, by default it differs from run to run.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restarts random picks from the seed, runs with the same seed and answers go the same way.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng.set(Rng::new(seed));
+    }
+
+    /// Runs `pick` on the shared generator.
+    fn random<T>(&self, pick: impl FnOnce(&mut Rng) -> T) -> T {
+        let mut rng = self.rng.get();
+        let value = pick(&mut rng);
+        self.rng.set(rng);
+        value
+    }
+
+    /// Stores picked option of the pending choice, so execution can go on.
+    pub fn answer<'a>(
+        &mut self,
+        env: &mut dyn Environment,
+        answer: impl Into<Answer<'a>>,
+    ) -> Result<(), AnswerError> {
+        let (store_to, options, _) = self.pending_choice.as_ref().ok_or(AnswerError::NotAsked)?;
+        let (name, _, enabled) = match answer.into() {
+            Answer::Index(index) => options.get(index).ok_or(AnswerError::OutOfRange(index))?,
+            Answer::Name(name) => options
+                .iter()
+                .find(|(item, _, _)| item.as_str() == name)
+                .ok_or_else(|| AnswerError::Unknown(name.to_owned()))?,
+        };
+        if !enabled {
+            return Err(AnswerError::Disabled(name.clone()));
+        }
+        env.set(store_to.as_str(), Variant::String(name.as_rc().clone()));
+        self.pending_choice = None;
+        Ok(())
+    }
+
+    /// Whether execution waits for [`DirectExecution::answer`].
+    pub fn is_waiting_answer(&self) -> bool {
+        self.pending_choice.is_some()
+    }
+
+    /// Stores result of the pending trigger, so execution can go on.
+    pub fn resume(
+        &mut self,
+        env: &mut dyn Environment,
+        result: Variant,
+    ) -> Result<(), AnswerError> {
+        let (_, _, store_to) = self.pending_trigger.take().ok_or(AnswerError::NotAsked)?;
+        env.set(store_to.as_str(), result);
+        Ok(())
+    }
+
+    /// Whether execution waits for [`DirectExecution::resume`].
+    pub fn is_waiting_result(&self) -> bool {
+        self.pending_trigger.is_some()
+    }
+
+    /// Saves where execution is, so it can be continued by [`DirectExecution::restore`]
+    /// even after the game is restarted. If the last step was a text, it is shown again
+    /// after restore. Variables belong to the environment, they aren't saved.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let pending_choice = self.pending_choice.as_ref().map(|(_, options, _)| {
+            options
+                .iter()
+                .map(|(name, _, enabled)| (name.clone(), *enabled))
+                .collect()
+        });
+        let snapshot = Snapshot {
+            script_hash: self.script.content_hash(),
+            code_ptr: self.shown_text.unwrap_or(self.code_ptr),
+            call_stack: self.call_stack.clone(),
+            pending_choice,
+            pending_trigger: self
+                .pending_trigger
+                .as_ref()
+                .map(|(_, args, _)| args.clone()),
+            seed: self.seed,
+            rng: self.rng.get().state(),
+        };
+        let mut payload = Vec::new();
+        snapshot.encode(&mut payload);
+        binary::SNAPSHOT.wrap(&payload)
+    }
+
+    /// Continues execution saved by [`DirectExecution::snapshot`], the next step repeats
+    /// the shown text or pending choice or trigger. The script has to be the same
+    /// as when the snapshot was taken, translation may differ.
+    pub fn restore(script: &Rc<DirectScript>, bytes: &[u8]) -> Result<Self, DialogError> {
+        let snapshot = binary::SNAPSHOT.unwrap(bytes, Snapshot::decode)?;
+        if snapshot.script_hash != script.content_hash() {
+            return Err(DialogError::new(ErrorKind::ScriptChanged, None));
+        }
+        let error =
+            |message: &str| DialogError::new(ErrorKind::BadSnapshot(message.to_owned()), None);
+        let code = &script.code;
+        // Position right after the last command is fine, it ends the dialog
+        if snapshot.code_ptr > code.len()
+            || snapshot.call_stack.len() > MAX_CALL_DEPTH
+            || snapshot.call_stack.iter().any(|ptr| *ptr > code.len())
+        {
+            return Err(error("position is outside of the script"));
+        }
+        // Pending command is the one right before the position
+        let previous = snapshot
+            .code_ptr
+            .checked_sub(1)
+            .and_then(|ptr| code.get(ptr));
+        let pending_choice = match (snapshot.pending_choice, previous) {
+            (None, _) => None,
+            (Some(offered), Some(Command::Choice(store_to, what, tags))) => {
+                let (_, options) = &script.choices[*what as usize];
+                let texts = &script.option_texts[*what as usize];
+                let offered = offered
+                    .into_iter()
+                    .map(|(name, enabled)| {
+                        let index = options
+                            .iter()
+                            .position(|(item, _, _)| *item == name)
+                            .ok_or_else(|| error("pending choice has unknown option"))?;
+                        Ok((name, texts[index].clone(), enabled))
+                    })
+                    .collect::<Result<_, DialogError>>()?;
+                let store_to = script.strings[*store_to as usize].clone();
+                Some((store_to, offered, tags.clone()))
+            }
+            (Some(_), _) => return Err(error("choice is pending outside of a choice")),
+        };
+        let pending_trigger = match (snapshot.pending_trigger, previous) {
+            (None, _) => None,
+            (Some(args), Some(Command::Trigger(what, _, Some(store_to)))) => {
+                let what = script.strings[*what as usize].clone();
+                Some((what, args, script.strings[*store_to as usize].clone()))
+            }
+            (Some(_), _) => return Err(error("trigger is pending outside of a trigger")),
+        };
+        Ok(DirectExecution {
+            script: script.clone(),
+            code_ptr: snapshot.code_ptr,
+            call_stack: snapshot.call_stack,
+            pending_choice,
+            pending_trigger,
+            missing_formatter: Box::new(|name| format!("{{{name}}}")),
+            seed: snapshot.seed,
+            rng: Cell::new(Rng::new(snapshot.rng)),
+            shown_text: None,
+        })
+    }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Variant {
     String(Rc<str>),
     Int(i32),
+    Float(f64),
     Boolean(bool),
 }
 
@@ -100,71 +703,383 @@ impl Variant {
         match self {
             Variant::String(string) => !string.is_empty(),
             Variant::Int(num) => *num != 0,
+            Variant::Float(num) => *num != 0.0,
             Variant::Boolean(boolean) => *boolean,
         }
     }
+
+    /// Converts value to either int or float.
+    /// Strings that are not a number are treated as int zero, so `add gold 5`
+    /// on a text variable replaces it with `5`; scripts rely on this for absent values too.
+    fn to_number(&self) -> Variant {
+        match self {
+            Variant::String(string) => {
+                let string = string.trim();
+                if let Ok(num) = string.parse() {
+                    Variant::Int(num)
+                } else {
+                    match string.parse::<f64>() {
+                        Ok(num) if num.is_finite() => Variant::Float(num),
+                        _ => Variant::Int(0),
+                    }
+                }
+            }
+            Variant::Int(_) | Variant::Float(_) => self.clone(),
+            Variant::Boolean(boolean) => Variant::Int(*boolean as i32),
+        }
+    }
+
+    fn to_float(&self) -> f64 {
+        match self.to_number() {
+            Variant::Int(num) => num as f64,
+            Variant::Float(num) => num,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Display for Variant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Variant::String(string) => f.write_str(string),
+            Variant::Int(num) => write!(f, "{num}"),
+            Variant::Float(num) => write!(f, "{num}"),
+            Variant::Boolean(boolean) => write!(f, "{boolean}"),
+        }
+    }
+}
+
+/// Int and float are equal if they represent the same number.
+impl PartialEq for Variant {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Variant::String(lhs), Variant::String(rhs)) => lhs == rhs,
+            (Variant::Int(lhs), Variant::Int(rhs)) => lhs == rhs,
+            (Variant::Float(lhs), Variant::Float(rhs)) => lhs == rhs,
+            (Variant::Int(int), Variant::Float(float))
+            | (Variant::Float(float), Variant::Int(int)) => *int as f64 == *float,
+            (Variant::Boolean(lhs), Variant::Boolean(rhs)) => lhs == rhs,
+            _ => false,
+        }
+    }
 }
 
+/// Visit counters of labels are kept here too, as ints named `@visits:<label>`,
+/// so they are saved together with other variables.
 pub trait Environment {
     fn get(&self, name: &str) -> Option<Variant>;
     fn set(&mut self, name: &str, value: Variant);
 }
 
 impl DirectExecution {
+    /// Runs until something has to be shown. While a choice isn't answered,
+    /// it is offered again instead of going further.
     pub fn step(&mut self, env: &mut dyn Environment) -> ExecutionStep {
+        self.shown_text = None;
+        if let Some((store_to, options, tags)) = &self.pending_choice {
+            return ExecutionStep::Choice(store_to.clone(), options.clone(), tags.clone());
+        }
+        if let Some((what, arguments, store_to)) = &self.pending_trigger {
+            let store_to = Some(store_to.clone());
+            return ExecutionStep::Trigger(what.clone(), arguments.clone(), store_to);
+        }
         loop {
-            let command = &self.script.code[self.code_ptr];
+            // Running past the last command ends the dialog
+            let Some(command) = self.script.code.get(self.code_ptr) else {
+                return ExecutionStep::End;
+            };
             match command {
                 // Control Flow
                 Command::Jump(jump_to) => self.code_ptr = *jump_to,
-                Command::EvalCondition(condition) => {
-                    self.last_condition = match condition {
-                        Condition::Var(var) => self.get_variant(env, var).unwrap().to_bool(),
-                        Condition::Expr(rhs, logic_op, lhs) => {
-                            let rhs = self.get_variant(env, rhs).unwrap();
-                            let lhs = self.get_variant(env, lhs).unwrap();
-                            match logic_op {
-                                LogicOperation::Equal => rhs == lhs,
-                                LogicOperation::NotEqual => rhs != lhs,
-                            }
-                        }
+                Command::Call(call) => {
+                    // Runaway recursion stops the dialog instead of growing forever
+                    if self.call_stack.len() >= MAX_CALL_DEPTH {
+                        return ExecutionStep::Error(RuntimeError::CallTooDeep);
+                    }
+                    self.call_stack.push(self.code_ptr + 1);
+                    self.code_ptr = *call;
+                }
+                // Returning from the label execution was started at ends it
+                Command::Return => match self.call_stack.pop() {
+                    Some(return_to) => self.code_ptr = return_to,
+                    None => return ExecutionStep::End,
+                },
+                // Variables
+                Command::Set(name, value) => {
+                    let name = self.script.strings[*name as usize].clone();
+                    // Copying absent variable leaves the target untouched
+                    if let Some(value) = self.evaluate(env, value) {
+                        env.set(name.as_str(), value);
+                    }
+                    self.code_ptr += 1;
+                }
+                Command::Add(name, value) | Command::Sub(name, value) => {
+                    let name = self.script.strings[*name as usize].clone();
+                    let op = if matches!(command, Command::Add(..)) {
+                        BinaryOperation::Add
+                    } else {
+                        BinaryOperation::Sub
                     };
+                    let value = self.evaluate(env, value);
+                    let result = arithmetic(&op, env.get(name.as_str()), value);
+                    env.set(name.as_str(), result);
+                    self.code_ptr += 1;
+                }
+                Command::Switch(name, table, otherwise) => {
+                    let name = self.script.strings[*name as usize].as_str();
+                    let answer = env.get(name);
+                    let target = table.iter().find(|(option, _)| {
+                        let option = self.script.strings[*option as usize].as_str();
+                        matches!(&answer, Some(Variant::String(answer)) if **answer == *option)
+                    });
+                    self.code_ptr = target.map_or(*otherwise, |(_, target)| *target);
+                }
+                Command::Random(branches) => {
+                    let total = branches.iter().map(|(weight, _)| *weight as u64).sum();
+                    let mut roll = self.random(|rng| rng.below(total));
+                    for (weight, target) in branches {
+                        if roll < *weight as u64 {
+                            self.code_ptr = *target;
+                            break;
+                        }
+                        roll -= *weight as u64;
+                    }
+                }
+                Command::Visit(counter) => {
+                    let name = self.script.strings[*counter as usize].as_str();
+                    let count =
+                        arithmetic(&BinaryOperation::Add, env.get(name), Some(Variant::Int(1)));
+                    env.set(name, count);
                     self.code_ptr += 1;
                 }
-                Command::If(skip) => {
-                    if self.last_condition {
+                Command::If(condition, skip) => {
+                    let condition = self.evaluate(env, condition);
+                    if condition.is_some_and(|var| var.to_bool()) {
                         self.code_ptr += 1;
                     } else {
                         self.code_ptr += *skip;
                     }
                 }
                 // User related things
-                Command::Text(who, says) => {
-                    let who = who.map(|who| self.script.strings[who.get() as usize].clone());
-                    let (says, stops) = &self.script.texts[*says as usize];
-                    let stops = stops
+                Command::Text(who, portrait, says, tags) => {
+                    let who = who.map(|who| {
+                        let id = &self.script.strings[who.get() as usize];
+                        let (_, character) = self
+                            .script
+                            .characters
+                            .iter()
+                            .find(|(item, _)| item == id)
+                            .expect("Speakers are checked when script is built");
+                        let portrait = portrait
+                            .map(|tag| self.script.strings[tag.get() as usize].clone())
+                            .or_else(|| character.portrait.clone());
+                        Speaker {
+                            id: id.clone(),
+                            character: character.clone(),
+                            portrait,
+                        }
+                    });
+                    let (says, stops, styles) = &self.script.texts[*says as usize];
+                    let mut stops = stops.to_vec();
+                    let mut styles = styles.to_vec();
+                    let says = self.interpolate(env, says, &mut stops, &mut styles);
+                    self.shown_text = Some(self.code_ptr);
+                    self.code_ptr += 1;
+                    return ExecutionStep::Text(who, says, stops, styles, tags.clone());
+                }
+                Command::Choice(store_to_idx, what, tags) => {
+                    let store_to = self.script.strings[*store_to_idx as usize].clone();
+                    let holds = |condition| {
+                        let value = self.evaluate(env, condition);
+                        value.is_some_and(|var| var.to_bool())
+                    };
+                    let texts = &self.script.option_texts[*what as usize];
+                    let what: ChoiceVariants = self.script.choices[*what as usize]
+                        .1
                         .iter()
-                        .flat_map(|item| item.map(|item| item.get() as usize))
+                        .zip(texts)
+                        .filter_map(|((name, _, condition), text)| {
+                            let enabled = match condition {
+                                OptionCondition::Always => true,
+                                OptionCondition::ShownIf(condition) => {
+                                    if !holds(condition) {
+                                        return None;
+                                    }
+                                    true
+                                }
+                                OptionCondition::EnabledIf(condition) => holds(condition),
+                            };
+                            Some((name.clone(), text.clone(), enabled))
+                        })
                         .collect();
                     self.code_ptr += 1;
-                    return ExecutionStep::Text(who, says.clone(), stops);
+                    // Nothing can be picked, so the choice is skipped and `store_to` isn't set.
+                    // Menu goes past its branches, an old answer mustn't pick one of them
+                    if !what.iter().any(|(_, _, enabled)| *enabled) {
+                        if let Some(Command::Switch(name, _, end)) =
+                            self.script.code.get(self.code_ptr)
+                            && name == store_to_idx
+                        {
+                            self.code_ptr = *end;
+                        }
+                        continue;
+                    }
+                    self.pending_choice = Some((store_to.clone(), what.clone(), tags.clone()));
+                    return ExecutionStep::Choice(store_to, what, tags.clone());
                 }
-                Command::Choice(store_to, what) => {
-                    let store_to = self.script.strings[*store_to as usize].clone();
-                    let what = self.script.choices[*what as usize].1.clone();
+                Command::Trigger(what, arguments, store_to) => {
+                    let what = self.script.strings[*what as usize].clone();
+                    // Absent variables are passed as false
+                    let arguments: Vec<_> = arguments
+                        .iter()
+                        .map(|arg| self.evaluate(env, arg).unwrap_or(Variant::Boolean(false)))
+                        .collect();
+                    let store_to = store_to.map(|name| self.script.strings[name as usize].clone());
+                    if let Some(store_to) = &store_to {
+                        self.pending_trigger =
+                            Some((what.clone(), arguments.clone(), store_to.clone()));
+                    }
                     self.code_ptr += 1;
-                    return ExecutionStep::Choice(store_to, what);
+                    return ExecutionStep::Trigger(what, arguments, store_to);
                 }
-                Command::Trigger(what) => {
-                    let what = self.script.strings[*what as usize].clone();
+                Command::Wait(seconds) => {
                     self.code_ptr += 1;
-                    return ExecutionStep::Trigger(what);
+                    return ExecutionStep::Wait(*seconds);
                 }
                 Command::End => return ExecutionStep::End,
             }
         }
     }
 
+    /// Substitutes variables into the text, moving stops (in characters)
+    /// and styles (in bytes) accordingly.
+    fn interpolate(
+        &self,
+        env: &dyn Environment,
+        text: &Text,
+        stops: &mut [Stop],
+        styles: &mut [StyledSpan],
+    ) -> Text {
+        if !has_placeholders(text.as_str()) {
+            return text.clone();
+        }
+        let pieces = parse_template(text.as_str()).expect("Templates are checked by parser");
+        let original_stops: Vec<_> = stops.iter().map(|stop| stop.position).collect();
+        let original_styles: Vec<_> = styles
+            .iter()
+            .flat_map(|span| [span.range.start, span.range.end])
+            .collect();
+        let mut result = String::with_capacity(text.as_str().len());
+        // Character position of the current piece inside of the source text
+        let mut position = 0;
+        for (range, piece) in pieces {
+            let length = result.len();
+            match piece {
+                Piece::Literal(literal) => result.push_str(literal),
+                Piece::Escaped(brace) => result.push(brace),
+                Piece::Variable(name) => match env.get(name) {
+                    Some(value) => result.push_str(&value.to_string()),
+                    None => result.push_str(&(self.missing_formatter)(name)),
+                },
+            }
+            let added = result.len() - length;
+            let added_chars = result[length..].chars().count();
+            let piece_chars = text.as_str()[range.clone()].chars().count();
+            // Position right at the start of the piece stays before it
+            for (stop, original) in stops.iter_mut().zip(&original_stops) {
+                if *original > position {
+                    stop.position = stop.position + added_chars - piece_chars;
+                }
+            }
+            let bounds = styles
+                .iter_mut()
+                .flat_map(|span| [&mut span.range.start, &mut span.range.end]);
+            for (bound, original) in bounds.zip(&original_styles) {
+                if *original > range.start {
+                    *bound = *bound + added - range.len();
+                }
+            }
+            position += piece_chars;
+        }
+        result.as_str().into()
+    }
+
+    /// Absent variables are falsy, counted as zero and equal only to other absent variables.
+    fn evaluate(&self, env: &dyn Environment, expr: &Expression) -> Option<Variant> {
+        fn to_bool(value: Option<Variant>) -> bool {
+            value.is_some_and(|var| var.to_bool())
+        }
+
+        let (lhs, op, rhs) = match expr {
+            Expression::Var(var) => return self.get_variant(env, var),
+            Expression::Unary(op, expr) => {
+                let value = self.evaluate(env, expr);
+                return Some(match op {
+                    UnaryOperation::Not => Variant::Boolean(!to_bool(value)),
+                    UnaryOperation::Negate => {
+                        match value.map_or(Variant::Int(0), |v| v.to_number()) {
+                            Variant::Int(num) => Variant::Int(num.saturating_neg()),
+                            num => Variant::Float(-num.to_float()),
+                        }
+                    }
+                });
+            }
+            Expression::Binary(lhs, op, rhs) => (lhs, op, rhs),
+            Expression::Visits(counter) => {
+                let name = self.script.strings.get(*counter as usize)?;
+                return Some(env.get(name.as_str()).unwrap_or(Variant::Int(0)));
+            }
+            Expression::Random(min, max) => {
+                let to_number =
+                    |value: Option<Variant>| value.map_or(Variant::Int(0), |v| v.to_number());
+                let min = to_number(self.evaluate(env, min));
+                let max = to_number(self.evaluate(env, max));
+                return Some(match (min, max) {
+                    (Variant::Int(min), Variant::Int(max)) => {
+                        Variant::Int(self.random(|rng| rng.int_in(min, max)))
+                    }
+                    (min, max) => {
+                        let (min, max) = (min.to_float(), max.to_float());
+                        Variant::Float(min + self.random(Rng::unit) * (max - min))
+                    }
+                });
+            }
+        };
+        // Logic operations are short-circuiting
+        match op {
+            BinaryOperation::Or => {
+                let value = to_bool(self.evaluate(env, lhs)) || to_bool(self.evaluate(env, rhs));
+                return Some(Variant::Boolean(value));
+            }
+            BinaryOperation::And => {
+                let value = to_bool(self.evaluate(env, lhs)) && to_bool(self.evaluate(env, rhs));
+                return Some(Variant::Boolean(value));
+            }
+            _ => {}
+        }
+        let lhs = self.evaluate(env, lhs);
+        let rhs = self.evaluate(env, rhs);
+        let value = match op {
+            BinaryOperation::Or | BinaryOperation::And => unreachable!(),
+            BinaryOperation::Equal => Variant::Boolean(lhs == rhs),
+            BinaryOperation::NotEqual => Variant::Boolean(lhs != rhs),
+            BinaryOperation::Less => {
+                Variant::Boolean(compare(lhs, rhs).is_some_and(Ordering::is_lt))
+            }
+            BinaryOperation::LessEqual => {
+                Variant::Boolean(compare(lhs, rhs).is_some_and(Ordering::is_le))
+            }
+            BinaryOperation::Greater => {
+                Variant::Boolean(compare(lhs, rhs).is_some_and(Ordering::is_gt))
+            }
+            BinaryOperation::GreaterEqual => {
+                Variant::Boolean(compare(lhs, rhs).is_some_and(Ordering::is_ge))
+            }
+            _ => arithmetic(op, lhs, rhs),
+        };
+        Some(value)
+    }
+
     fn get_variant<'a>(&'a self, env: &'a dyn Environment, var: &Variable) -> Option<Variant> {
         match var {
             Variable::Name(ident) => {
@@ -177,41 +1092,148 @@ impl DirectExecution {
                 Some(Variant::String(text.as_rc().clone()))
             }
             Variable::Int(value) => Some(Variant::Int(*value)),
+            Variable::Float(value) => Some(Variant::Float(*value)),
+        }
+    }
+}
+
+/// Numbers are compared by value, absent variables are treated as zero.
+fn compare(lhs: Option<Variant>, rhs: Option<Variant>) -> Option<Ordering> {
+    let lhs = lhs.map_or(Variant::Int(0), |var| var.to_number());
+    let rhs = rhs.map_or(Variant::Int(0), |var| var.to_number());
+    match (lhs, rhs) {
+        (Variant::Int(lhs), Variant::Int(rhs)) => Some(lhs.cmp(&rhs)),
+        (lhs, rhs) => lhs.to_float().partial_cmp(&rhs.to_float()),
+    }
+}
+
+/// Ints stay ints and saturate on overflow, mixing them with floats gives float.
+/// Division by zero results in zero and `+` concatenates two strings.
+fn arithmetic(op: &BinaryOperation, lhs: Option<Variant>, rhs: Option<Variant>) -> Variant {
+    if let (BinaryOperation::Add, Some(Variant::String(lhs)), Some(Variant::String(rhs))) =
+        (op, &lhs, &rhs)
+    {
+        return Variant::String(format!("{lhs}{rhs}").into());
+    }
+    let lhs = lhs.map_or(Variant::Int(0), |var| var.to_number());
+    let rhs = rhs.map_or(Variant::Int(0), |var| var.to_number());
+    match (lhs, rhs) {
+        (Variant::Int(lhs), Variant::Int(rhs)) => Variant::Int(match op {
+            BinaryOperation::Add => lhs.saturating_add(rhs),
+            BinaryOperation::Sub => lhs.saturating_sub(rhs),
+            BinaryOperation::Mul => lhs.saturating_mul(rhs),
+            BinaryOperation::Div => lhs.checked_div(rhs).unwrap_or(0),
+            BinaryOperation::Rem => lhs.checked_rem(rhs).unwrap_or(0),
+            _ => panic!("Not an arithmetic operation: {op:?}"),
+        }),
+        (lhs, rhs) => {
+            let (lhs, rhs) = (lhs.to_float(), rhs.to_float());
+            Variant::Float(match op {
+                BinaryOperation::Add => lhs + rhs,
+                BinaryOperation::Sub => lhs - rhs,
+                BinaryOperation::Mul => lhs * rhs,
+                BinaryOperation::Div if rhs == 0.0 => 0.0,
+                BinaryOperation::Div => lhs / rhs,
+                BinaryOperation::Rem if rhs == 0.0 => 0.0,
+                BinaryOperation::Rem => lhs % rhs,
+                _ => panic!("Not an arithmetic operation: {op:?}"),
+            })
         }
     }
 }
 
-fn construct_script_from_ast(ast_tree: &[AstNode]) -> DirectScript {
-    let choices: Box<_> = ast_tree
+fn construct_script_from_ast(ast_tree: &[AstNode]) -> Result<DirectScript, DialogError> {
+    let mut code = Vec::new();
+    let mut strings = vec!["safe-guard: probably a bug!".into()];
+    let mut choices: Vec<_> = ast_tree
         .iter()
         .filter_map(|node| match node {
             AstNode::Choices(ident, content) => {
-                let content = content.clone().into();
+                let content = content
+                    .iter()
+                    .map(|(name, text, condition)| {
+                        let condition = convert_condition(condition, &mut strings);
+                        (name.clone(), text.clone(), condition)
+                    })
+                    .collect();
                 Some((ident.clone(), content))
             }
             _ => None,
         })
         .collect();
-    let mut code = Vec::new();
-    let mut strings = vec!["safe-guard: probably a bug!".into()];
-    let mut texts = Vec::new();
+    let characters: Vec<_> = ast_tree
+        .iter()
+        .filter_map(|node| match node {
+            AstNode::Character(ident, character) => {
+                Some((ident.clone(), Rc::new(character.clone())))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut lines = Vec::new();
     let mut labels = Vec::new();
+    // Jumps waiting for their label: (position in code, label)
+    let mut jumps = Vec::new();
 
     fn count_op(node: &AstNode) -> usize {
         match node {
+            // Labels only count visits, jumps lead to that
             AstNode::Label(_) => 1,
             AstNode::Command(_) => 1,
-            AstNode::Dialog(_, _) => 1,
+            AstNode::Dialog(..) => 1,
             AstNode::Choices(_, _) => 1,
-            AstNode::LabelBlock(_, nodes) => nodes.iter().map(count_op).sum(),
-            AstNode::IfBlock(_, nodes, else_nods) => {
-                // Adds 2 commands: condition and if
-                let if_part = 2 + nodes.iter().map(count_op).sum::<usize>();
-                let else_part = else_nods.as_ref().map_or(0, |nodes| {
-                    // Adds new jump to avoid else part if "if" part was executed
-                    1 + nodes.iter().map(count_op).sum::<usize>()
-                });
-                if_part + else_part
+            AstNode::Character(_, _) => 0,
+            AstNode::LabelBlock(_, nodes) => 1 + nodes.iter().map(count_op).sum::<usize>(),
+            AstNode::IfBlock(branches, else_nodes) => {
+                // Every branch adds 2 commands: if and jump to the end
+                let branches_part = branches
+                    .iter()
+                    .map(|(_, nodes)| 2 + nodes.iter().map(count_op).sum::<usize>())
+                    .sum::<usize>();
+                let else_part = else_nodes
+                    .as_ref()
+                    .map_or(0, |nodes| nodes.iter().map(count_op).sum::<usize>());
+                // Last branch doesn't need a jump if there is nothing after it
+                branches_part - else_nodes.is_none() as usize + else_part
+            }
+            AstNode::Random(branches) => {
+                // Random itself, then every non-empty body with jump to the end
+                let bodies = branches
+                    .iter()
+                    .map(|(_, nodes)| nodes.iter().map(count_op).sum::<usize>())
+                    .map(|size| if size > 0 { size + 1 } else { 0 })
+                    .sum::<usize>();
+                1 + bodies
+            }
+            AstNode::Menu(_, _, _, options) => {
+                // Choice and switch, then every non-empty body with jump to the end
+                let bodies = options
+                    .iter()
+                    .map(|(_, _, _, nodes)| nodes.iter().map(count_op).sum::<usize>())
+                    .map(|size| if size > 0 { size + 1 } else { 0 })
+                    .sum::<usize>();
+                2 + bodies
+            }
+            // If on the visit counter, then the body
+            AstNode::Once(_, nodes) => 1 + nodes.iter().map(count_op).sum::<usize>(),
+        }
+    }
+    fn convert_visits(label: &Identifier, strings: &mut Vec<Identifier>) -> u32 {
+        let counter: Identifier = format!("@visits:{}", label.as_str()).as_str().into();
+        strings.push_unique(&counter);
+        strings.iter().position(|item| *item == counter).unwrap() as u32
+    }
+    fn convert_condition(
+        condition: &crate::grammar::OptionCondition,
+        strings: &mut Vec<Identifier>,
+    ) -> OptionCondition {
+        match condition {
+            crate::grammar::OptionCondition::Always => OptionCondition::Always,
+            crate::grammar::OptionCondition::ShownIf(condition) => {
+                OptionCondition::ShownIf(convert_expr(condition, strings))
+            }
+            crate::grammar::OptionCondition::EnabledIf(condition) => {
+                OptionCondition::EnabledIf(convert_expr(condition, strings))
             }
         }
     }
@@ -230,151 +1252,645 @@ fn construct_script_from_ast(ast_tree: &[AstNode]) -> DirectScript {
             }
             crate::grammar::Variable::Boolean(value) => Variable::Boolean(*value),
             crate::grammar::Variable::Int(value) => Variable::Int(*value),
+            crate::grammar::Variable::Float(value) => Variable::Float(*value),
+        }
+    }
+    fn convert_expr(
+        expr: &crate::grammar::Expression,
+        strings: &mut Vec<Identifier>,
+    ) -> Expression {
+        match expr {
+            crate::grammar::Expression::Variable(var) => Expression::Var(convert_var(var, strings)),
+            crate::grammar::Expression::Unary(op, expr) => {
+                Expression::Unary(op.into(), Box::new(convert_expr(expr, strings)))
+            }
+            crate::grammar::Expression::Binary(lhs, op, rhs) => {
+                let lhs = convert_expr(lhs, strings);
+                let rhs = convert_expr(rhs, strings);
+                Expression::Binary(Box::new(lhs), op.into(), Box::new(rhs))
+            }
+            crate::grammar::Expression::Random(min, max) => {
+                let min = convert_expr(min, strings);
+                let max = convert_expr(max, strings);
+                Expression::Random(Box::new(min), Box::new(max))
+            }
+            crate::grammar::Expression::Visits(label) => {
+                Expression::Visits(convert_visits(label, strings))
+            }
         }
     }
     fn convert(
         node: &AstNode,
         code: &mut Vec<Command>,
         strings: &mut Vec<Identifier>,
-        texts: &mut Vec<(Text, [Option<std::num::NonZero<u16>>; 12])>,
+        lines: &mut Vec<(Identifier, Text)>,
         labels: &mut Vec<(Identifier, usize)>,
-        choices: &[(Identifier, ChoiceVariants)],
-    ) {
+        choices: &mut Vec<(Identifier, ChoiceOptions)>,
+        jumps: &mut Vec<(usize, Identifier)>,
+    ) -> Result<(), DialogError> {
         match node {
             AstNode::Label(ident) => {
                 labels.push((ident.clone(), code.len()));
+                code.push(Command::Visit(convert_visits(ident, strings)));
             }
             AstNode::Command(command) => {
                 let command = match command {
                     crate::grammar::Command::End => Command::End,
                     crate::grammar::Command::Jump(ident) => {
-                        if let Some((_, jump_to)) = labels.iter().find(|(item, _)| item == ident) {
-                            Command::Jump(*jump_to)
-                        } else {
-                            // TODO: how we will do it?
-                            todo!("Currently all jumps should be to already defined labels");
-                        }
+                        // Target may be defined later, so it is patched after all code is built
+                        jumps.push((code.len(), ident.clone()));
+                        Command::Jump(usize::MAX)
+                    }
+                    crate::grammar::Command::Call(ident) => {
+                        jumps.push((code.len(), ident.clone()));
+                        Command::Call(usize::MAX)
                     }
-                    crate::grammar::Command::Choice(where_to, what) => {
+                    crate::grammar::Command::Return => Command::Return,
+                    crate::grammar::Command::Choice(where_to, what, tags) => {
                         strings.push_unique(where_to);
                         let where_to = strings.iter().position(|item| item == where_to).unwrap();
-                        let what = choices.iter().position(|(item, _)| item == what).unwrap();
-                        Command::Choice(where_to as u32, what as u32)
+                        let what = choices
+                            .iter()
+                            .position(|(item, _)| item == what)
+                            .ok_or_else(|| {
+                                DialogError::new(ErrorKind::UndefinedChoice(what.clone()), None)
+                            })?;
+                        Command::Choice(where_to as u32, what as u32, tags.as_slice().into())
                     }
-                    crate::grammar::Command::Trigger(what) => {
+                    crate::grammar::Command::Trigger(what, arguments, store_to) => {
                         strings.push_unique(what);
                         let what = strings.iter().position(|item| item == what).unwrap();
-                        Command::Trigger(what as u32)
+                        let arguments = arguments
+                            .iter()
+                            .map(|arg| convert_expr(arg, strings))
+                            .collect();
+                        let store_to = store_to.as_ref().map(|name| {
+                            strings.push_unique(name);
+                            strings.iter().position(|item| item == name).unwrap() as u32
+                        });
+                        Command::Trigger(what as u32, arguments, store_to)
+                    }
+                    crate::grammar::Command::Wait(seconds) => Command::Wait(*seconds),
+                    crate::grammar::Command::Set(name, value)
+                    | crate::grammar::Command::Add(name, value)
+                    | crate::grammar::Command::Sub(name, value) => {
+                        strings.push_unique(name);
+                        let name = strings.iter().position(|item| item == name).unwrap() as u32;
+                        let value = convert_expr(value, strings);
+                        match command {
+                            crate::grammar::Command::Set(..) => Command::Set(name, value),
+                            crate::grammar::Command::Add(..) => Command::Add(name, value),
+                            _ => Command::Sub(name, value),
+                        }
                     }
                 };
                 code.push(command);
             }
-            AstNode::Dialog(who, says) => {
-                let who = who.as_ref().and_then(|who| {
-                    strings.push_unique(who);
-                    NonZeroU32::new(strings.iter().position(|item| item == who).unwrap() as u32)
-                });
-                let indexes = says
+            AstNode::Dialog(who, portrait, says, id, tags) => {
+                let mut to_index = |ident: &Identifier| {
+                    strings.push_unique(ident);
+                    NonZeroU32::new(strings.iter().position(|item| item == ident).unwrap() as u32)
+                };
+                let who = who.as_ref().and_then(&mut to_index);
+                let portrait = portrait.as_ref().and_then(&mut to_index);
+                // Lines are compiled all at once, when the language is known
+                lines.push((
+                    id.clone(),
+                    join_parts(says.iter().map(Text::as_str)).as_str().into(),
+                ));
+                let says = lines.len() as u32 - 1;
+                code.push(Command::Text(who, portrait, says, tags.as_slice().into()));
+            }
+            AstNode::IfBlock(branches, else_nodes) => {
+                let end = code.len() + count_op(node);
+                for (i, (condition, nodes)) in branches.iter().enumerate() {
+                    let condition = convert_expr(condition, strings);
+                    let has_jump = i + 1 < branches.len() || else_nodes.is_some();
+                    let body_size = nodes.iter().map(count_op).sum::<usize>();
+                    // Skips if itself, the body and jump to the end
+                    code.push(Command::If(condition, 1 + body_size + has_jump as usize));
+                    for node in nodes {
+                        convert(node, code, strings, lines, labels, choices, jumps)?;
+                    }
+                    if has_jump {
+                        code.push(Command::Jump(end));
+                    }
+                }
+                if let Some(else_nodes) = else_nodes {
+                    for node in else_nodes {
+                        convert(node, code, strings, lines, labels, choices, jumps)?;
+                    }
+                }
+                debug_assert_eq!(code.len(), end, "Miscounted size of if block");
+            }
+            AstNode::Menu(store_to, id, tags, options) => {
+                let end = code.len() + count_op(node);
+                strings.push_unique(store_to);
+                let store_to = strings.iter().position(|item| item == store_to).unwrap() as u32;
+                // Menu is anonymous choice, its id can't clash with declared ones
+                let content = options
                     .iter()
-                    .map(|text| text.as_str().len())
-                    .scan(0, |sum, item| {
-                        *sum += item;
-                        Some(*sum)
+                    .map(|(name, text, condition, _)| {
+                        let condition = convert_condition(condition, strings);
+                        (name.clone(), text.clone(), condition)
                     })
-                    .collect::<Vec<_>>();
-                let indexes = {
-                    let mut values = [None; 12];
-                    for (i, val) in indexes.into_iter().enumerate().take(12) {
-                        values[i] = NonZeroU16::new(val as u16);
+                    .collect();
+                choices.push((id.clone(), content));
+                let what = choices.len() as u32 - 1;
+                code.push(Command::Choice(store_to, what, tags.as_slice().into()));
+                let switch = code.len();
+                code.push(Command::Switch(store_to, Box::new([]), end));
+                let mut table = Vec::with_capacity(options.len());
+                for (name, _, _, nodes) in options {
+                    strings.push_unique(name);
+                    let name = strings.iter().position(|item| item == name).unwrap() as u32;
+                    if nodes.is_empty() {
+                        table.push((name, end));
+                        continue;
                     }
-                    values
-                };
-                let text: Text = says
-                    .iter()
-                    .map(|text| text.as_str())
-                    .fold(String::new(), |a, b| a + b)
-                    .as_str()
-                    .into();
-                texts.push((text, indexes));
-                code.push(Command::Text(who, texts.len() as u32 - 1));
-            }
-            AstNode::IfBlock(condition, nodes, else_nodes) => {
-                let cond = match condition {
-                    crate::grammar::Condition::Variable(var) => {
-                        let var = convert_var(var, strings);
-                        Condition::Var(var)
-                    }
-                    crate::grammar::Condition::Expr(rhs, logic_op, lhs) => {
-                        let rhs = convert_var(rhs, strings);
-                        let lhs = convert_var(lhs, strings);
-                        Condition::Expr(rhs, logic_op.into(), lhs)
+                    table.push((name, code.len()));
+                    for node in nodes {
+                        convert(node, code, strings, lines, labels, choices, jumps)?;
                     }
-                };
-                code.push(Command::EvalCondition(cond));
-                let if_part_size = nodes.iter().map(count_op).sum::<usize>();
-                // because we will add 1 new jump if there is else part
-                code.push(Command::If(if_part_size + else_nodes.is_some() as usize));
-                nodes
-                    .iter()
-                    .for_each(|node| convert(node, code, strings, texts, labels, choices));
-                if let Some(else_nodes) = else_nodes {
-                    let else_part_size = else_nodes.iter().map(count_op).sum::<usize>();
-                    code.push(Command::Jump(code.len() + else_part_size));
-                    else_nodes
-                        .iter()
-                        .for_each(|node| convert(node, code, strings, texts, labels, choices));
+                    code.push(Command::Jump(end));
                 }
+                code[switch] = Command::Switch(store_to, table.into(), end);
+                debug_assert_eq!(code.len(), end, "Miscounted size of menu");
+            }
+            AstNode::Random(branches) => {
+                let end = code.len() + count_op(node);
+                let random = code.len();
+                code.push(Command::Random(Box::new([])));
+                let mut table = Vec::with_capacity(branches.len());
+                for (weight, nodes) in branches {
+                    if nodes.is_empty() {
+                        table.push((*weight, end));
+                        continue;
+                    }
+                    table.push((*weight, code.len()));
+                    for node in nodes {
+                        convert(node, code, strings, lines, labels, choices, jumps)?;
+                    }
+                    code.push(Command::Jump(end));
+                }
+                code[random] = Command::Random(table.into());
+                debug_assert_eq!(code.len(), end, "Miscounted size of random block");
+            }
+            AstNode::Once(label, nodes) => {
+                let end = code.len() + count_op(node);
+                // Label is entered before its body runs, so the first pass sees one visit
+                let visits = Expression::Visits(convert_visits(label, strings));
+                let first = Expression::Var(Variable::Int(1));
+                let condition = Expression::Binary(
+                    Box::new(visits),
+                    BinaryOperation::LessEqual,
+                    Box::new(first),
+                );
+                code.push(Command::If(condition, end - code.len()));
+                for node in nodes {
+                    convert(node, code, strings, lines, labels, choices, jumps)?;
+                }
+                debug_assert_eq!(code.len(), end, "Miscounted size of once block");
             }
             _ => unreachable!(),
         }
+        Ok(())
     }
 
     for node in ast_tree {
         let (ident, nodes) = match node {
-            AstNode::Choices(_, _) => continue,
+            AstNode::Choices(_, _) | AstNode::Character(_, _) => continue,
             AstNode::LabelBlock(ident, nodes) => (ident, nodes),
             _ => unreachable!(),
         };
         labels.push((ident.clone(), code.len()));
-        nodes.iter().for_each(|node| {
+        code.push(Command::Visit(convert_visits(ident, &mut strings)));
+        for node in nodes {
             convert(
                 node,
                 &mut code,
                 &mut strings,
-                &mut texts,
+                &mut lines,
                 &mut labels,
-                &choices,
-            )
-        });
+                &mut choices,
+                &mut jumps,
+            )?;
+        }
     }
 
-    DirectScript {
+    for (position, ident) in jumps {
+        let (_, jump_to) = labels
+            .iter()
+            .find(|(item, _)| item == &ident)
+            .ok_or_else(|| DialogError::new(ErrorKind::UndefinedLabel(ident.clone()), None))?;
+        code[position] = match code[position] {
+            Command::Call(_) => Command::Call(*jump_to),
+            _ => Command::Jump(*jump_to),
+        };
+    }
+
+    let speakers = code.iter().filter_map(|command| match command {
+        Command::Text(Some(who), ..) => Some(&strings[who.get() as usize]),
+        _ => None,
+    });
+    for who in speakers {
+        if !characters.iter().any(|(item, _)| item == who) {
+            let kind = ErrorKind::UndefinedCharacter(who.clone());
+            return Err(DialogError::new(kind, None));
+        }
+    }
+
+    let mut script = DirectScript {
         code: code.into_boxed_slice(),
         strings: strings.into_boxed_slice(),
-        texts: texts.into_boxed_slice(),
+        texts: Box::new([]),
+        lines: lines.into_boxed_slice(),
         labels: labels.into_boxed_slice(),
-        choices,
+        choices: choices.into_boxed_slice(),
+        option_texts: Box::new([]),
+        characters: characters.into_boxed_slice(),
         source: None,
-    }
+    };
+    // Without translation only markup of the source can be wrong
+    script
+        .set_translation(None)
+        .map_err(|err| match err.kind() {
+            ErrorKind::BadTranslation(message) => {
+                DialogError::new(ErrorKind::BadMarkup(message.clone()), None)
+            }
+            _ => err,
+        })?;
+    Ok(script)
 }
 
-impl From<&[AstNode]> for DirectScript {
-    fn from(value: &[AstNode]) -> Self {
+impl TryFrom<&[AstNode]> for DirectScript {
+    type Error = DialogError;
+
+    fn try_from(value: &[AstNode]) -> Result<Self, Self::Error> {
         construct_script_from_ast(value)
     }
 }
 
-impl From<crate::grammar::LogicOperation> for LogicOperation {
-    fn from(value: crate::grammar::LogicOperation) -> Self {
-        (&value).into()
+impl Encode for Command {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Command::Text(who, portrait, what, tags) => {
+                out.push(0);
+                who.encode(out);
+                portrait.encode(out);
+                what.encode(out);
+                tags.encode(out);
+            }
+            Command::Jump(to) => {
+                out.push(1);
+                to.encode(out);
+            }
+            Command::Call(to) => {
+                out.push(2);
+                to.encode(out);
+            }
+            Command::Return => out.push(3),
+            Command::Choice(store_to, what, tags) => {
+                out.push(4);
+                store_to.encode(out);
+                what.encode(out);
+                tags.encode(out);
+            }
+            Command::Trigger(what, args, store_to) => {
+                out.push(5);
+                what.encode(out);
+                args.encode(out);
+                store_to.encode(out);
+            }
+            Command::Wait(seconds) => {
+                out.push(6);
+                seconds.encode(out);
+            }
+            Command::Set(name, value) | Command::Add(name, value) | Command::Sub(name, value) => {
+                let tag = match self {
+                    Command::Set(_, _) => 7,
+                    Command::Add(_, _) => 8,
+                    _ => 9,
+                };
+                out.push(tag);
+                name.encode(out);
+                value.encode(out);
+            }
+            Command::End => out.push(10),
+            Command::If(condition, skip) => {
+                out.push(11);
+                condition.encode(out);
+                skip.encode(out);
+            }
+            Command::Switch(name, cases, otherwise) => {
+                out.push(12);
+                name.encode(out);
+                cases.encode(out);
+                otherwise.encode(out);
+            }
+            Command::Random(branches) => {
+                out.push(13);
+                branches.encode(out);
+            }
+            Command::Visit(counter) => {
+                out.push(14);
+                counter.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Command {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(match input.tag()? {
+            0 => Command::Text(
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+            ),
+            1 => Command::Jump(Decode::decode(input)?),
+            2 => Command::Call(Decode::decode(input)?),
+            3 => Command::Return,
+            4 => Command::Choice(
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+            ),
+            5 => Command::Trigger(
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+            ),
+            6 => Command::Wait(Decode::decode(input)?),
+            7 => Command::Set(Decode::decode(input)?, Decode::decode(input)?),
+            8 => Command::Add(Decode::decode(input)?, Decode::decode(input)?),
+            9 => Command::Sub(Decode::decode(input)?, Decode::decode(input)?),
+            10 => Command::End,
+            11 => Command::If(Decode::decode(input)?, Decode::decode(input)?),
+            12 => Command::Switch(
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+                Decode::decode(input)?,
+            ),
+            13 => Command::Random(Decode::decode(input)?),
+            14 => Command::Visit(Decode::decode(input)?),
+            other => return Err(format!("unknown command {other}")),
+        })
+    }
+}
+
+impl Encode for Snapshot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.script_hash.encode(out);
+        self.code_ptr.encode(out);
+        self.call_stack.encode(out);
+        self.pending_choice.encode(out);
+        self.pending_trigger.encode(out);
+        self.seed.encode(out);
+        self.rng.encode(out);
+    }
+}
+
+impl Decode for Snapshot {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(Snapshot {
+            script_hash: Decode::decode(input)?,
+            code_ptr: Decode::decode(input)?,
+            call_stack: Decode::decode(input)?,
+            pending_choice: Decode::decode(input)?,
+            pending_trigger: Decode::decode(input)?,
+            seed: Decode::decode(input)?,
+            rng: Decode::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Variant {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Variant::String(string) => {
+                out.push(0);
+                string.encode(out);
+            }
+            Variant::Int(num) => {
+                out.push(1);
+                num.encode(out);
+            }
+            Variant::Float(num) => {
+                out.push(2);
+                num.encode(out);
+            }
+            Variant::Boolean(boolean) => {
+                out.push(3);
+                boolean.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Variant {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(match input.tag()? {
+            0 => Variant::String(Decode::decode(input)?),
+            1 => Variant::Int(Decode::decode(input)?),
+            2 => Variant::Float(Decode::decode(input)?),
+            3 => Variant::Boolean(Decode::decode(input)?),
+            other => return Err(format!("unknown variant {other}")),
+        })
+    }
+}
+
+impl Encode for OptionCondition {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            OptionCondition::Always => out.push(0),
+            OptionCondition::ShownIf(condition) => {
+                out.push(1);
+                condition.encode(out);
+            }
+            OptionCondition::EnabledIf(condition) => {
+                out.push(2);
+                condition.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for OptionCondition {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(match input.tag()? {
+            0 => OptionCondition::Always,
+            1 => OptionCondition::ShownIf(Decode::decode(input)?),
+            2 => OptionCondition::EnabledIf(Decode::decode(input)?),
+            other => return Err(format!("unknown option condition {other}")),
+        })
+    }
+}
+
+impl Encode for Variable {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Variable::Name(name) => {
+                out.push(0);
+                name.encode(out);
+            }
+            Variable::Boolean(value) => {
+                out.push(1);
+                value.encode(out);
+            }
+            Variable::Text(text) => {
+                out.push(2);
+                text.encode(out);
+            }
+            Variable::Int(value) => {
+                out.push(3);
+                value.encode(out);
+            }
+            Variable::Float(value) => {
+                out.push(4);
+                value.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Variable {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(match input.tag()? {
+            0 => Variable::Name(Decode::decode(input)?),
+            1 => Variable::Boolean(Decode::decode(input)?),
+            2 => Variable::Text(Decode::decode(input)?),
+            3 => Variable::Int(Decode::decode(input)?),
+            4 => Variable::Float(Decode::decode(input)?),
+            other => return Err(format!("unknown variable {other}")),
+        })
+    }
+}
+
+impl Encode for UnaryOperation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for UnaryOperation {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(match input.tag()? {
+            0 => UnaryOperation::Not,
+            1 => UnaryOperation::Negate,
+            other => return Err(format!("unknown unary operation {other}")),
+        })
+    }
+}
+
+impl Encode for BinaryOperation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for BinaryOperation {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        Ok(match input.tag()? {
+            0 => BinaryOperation::Or,
+            1 => BinaryOperation::And,
+            2 => BinaryOperation::Equal,
+            3 => BinaryOperation::NotEqual,
+            4 => BinaryOperation::Less,
+            5 => BinaryOperation::LessEqual,
+            6 => BinaryOperation::Greater,
+            7 => BinaryOperation::GreaterEqual,
+            8 => BinaryOperation::Add,
+            9 => BinaryOperation::Sub,
+            10 => BinaryOperation::Mul,
+            11 => BinaryOperation::Div,
+            12 => BinaryOperation::Rem,
+            other => return Err(format!("unknown binary operation {other}")),
+        })
+    }
+}
+
+impl Encode for Expression {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Expression::Var(var) => {
+                out.push(0);
+                var.encode(out);
+            }
+            Expression::Unary(operation, operand) => {
+                out.push(1);
+                operation.encode(out);
+                operand.encode(out);
+            }
+            Expression::Binary(lhs, operation, rhs) => {
+                out.push(2);
+                lhs.encode(out);
+                operation.encode(out);
+                rhs.encode(out);
+            }
+            Expression::Random(from, to) => {
+                out.push(3);
+                from.encode(out);
+                to.encode(out);
+            }
+            Expression::Visits(counter) => {
+                out.push(4);
+                counter.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Expression {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, String> {
+        decode_expression(input, 0)
     }
 }
 
-impl From<&crate::grammar::LogicOperation> for LogicOperation {
-    fn from(value: &crate::grammar::LogicOperation) -> Self {
+/// Nested expressions are read recursively, so their depth is limited.
+fn decode_expression(input: &mut Reader<'_>, depth: usize) -> Result<Expression, String> {
+    if depth >= MAX_EXPRESSION_DEPTH {
+        return Err(format!(
+            "expression is nested deeper than {MAX_EXPRESSION_DEPTH} levels"
+        ));
+    }
+    let operand = |input: &mut Reader<'_>| decode_expression(input, depth + 1).map(Box::new);
+    Ok(match input.tag()? {
+        0 => Expression::Var(Decode::decode(input)?),
+        1 => Expression::Unary(Decode::decode(input)?, operand(input)?),
+        2 => Expression::Binary(operand(input)?, Decode::decode(input)?, operand(input)?),
+        3 => Expression::Random(operand(input)?, operand(input)?),
+        4 => Expression::Visits(Decode::decode(input)?),
+        other => return Err(format!("unknown expression {other}")),
+    })
+}
+
+impl From<&crate::grammar::UnaryOperation> for UnaryOperation {
+    fn from(value: &crate::grammar::UnaryOperation) -> Self {
+        match value {
+            crate::grammar::UnaryOperation::Not => Self::Not,
+            crate::grammar::UnaryOperation::Negate => Self::Negate,
+        }
+    }
+}
+
+impl From<&crate::grammar::BinaryOperation> for BinaryOperation {
+    fn from(value: &crate::grammar::BinaryOperation) -> Self {
         match value {
-            crate::grammar::LogicOperation::Equal => Self::Equal,
-            crate::grammar::LogicOperation::NotEqual => Self::NotEqual,
+            crate::grammar::BinaryOperation::Or => Self::Or,
+            crate::grammar::BinaryOperation::And => Self::And,
+            crate::grammar::BinaryOperation::Equal => Self::Equal,
+            crate::grammar::BinaryOperation::NotEqual => Self::NotEqual,
+            crate::grammar::BinaryOperation::Less => Self::Less,
+            crate::grammar::BinaryOperation::LessEqual => Self::LessEqual,
+            crate::grammar::BinaryOperation::Greater => Self::Greater,
+            crate::grammar::BinaryOperation::GreaterEqual => Self::GreaterEqual,
+            crate::grammar::BinaryOperation::Add => Self::Add,
+            crate::grammar::BinaryOperation::Sub => Self::Sub,
+            crate::grammar::BinaryOperation::Mul => Self::Mul,
+            crate::grammar::BinaryOperation::Div => Self::Div,
+            crate::grammar::BinaryOperation::Rem => Self::Rem,
         }
     }
 }
@@ -393,13 +1909,58 @@ impl Environment for HashMap<Rc<str>, Variant> {
 mod test {
     use std::{collections::HashMap, fs::read_to_string, rc::Rc};
 
-    use crate::{exec::DirectExecution, grammar::parse_to_ast, interpreter::DirectScript};
+    use crate::{
+        error::ErrorKind,
+        exec::{
+            DirectExecution, Environment, ExecutionStep, MAX_CALL_DEPTH, Stop, Style, StyledSpan,
+            Tags, Translation,
+        },
+        grammar::{MAX_EXPRESSION_DEPTH, parse_to_ast},
+        interpreter::{
+            AnswerError, Command, DirectScript, Expression, RuntimeError, UnaryOperation, Variable,
+            Variant,
+        },
+    };
+
+    fn compile(source: &str) -> Rc<DirectScript> {
+        let ast_tree = parse_to_ast(source).unwrap();
+        Rc::new(ast_tree.as_slice().try_into().unwrap())
+    }
+
+    fn positions(stops: &[Stop]) -> Vec<usize> {
+        stops.iter().map(|stop| stop.position).collect()
+    }
+
+    /// Runs execution until the end, collecting all shown texts.
+    /// Choices are answered with what is already stored in the environment.
+    fn collect_texts(
+        script: &Rc<DirectScript>,
+        label: &str,
+        env: &mut dyn Environment,
+    ) -> Vec<String> {
+        let mut exec = DirectExecution::start(script, label).unwrap();
+        let mut texts = Vec::new();
+        for _ in 0..100 {
+            match exec.step(env) {
+                ExecutionStep::Text(_, text, ..) => texts.push(text.as_str().to_owned()),
+                ExecutionStep::Choice(store_to, _, _) => {
+                    let Some(Variant::String(answer)) = env.get(store_to.as_str()) else {
+                        panic!("No answer for `{}` in environment", store_to.as_str());
+                    };
+                    exec.answer(env, &*answer).unwrap();
+                }
+                ExecutionStep::End => return texts,
+                _ => {}
+            }
+        }
+        panic!("Execution didn't finish in time, got: {texts:?}");
+    }
 
     #[test]
     fn create_from_source_file() {
         let source = read_to_string("./res/test.drs").unwrap();
         let ast_tree = parse_to_ast(&source).unwrap();
-        let script: Rc<DirectScript> = Rc::new(ast_tree.as_slice().into());
+        let script: Rc<DirectScript> = Rc::new(ast_tree.as_slice().try_into().unwrap());
 
         let mut env = HashMap::new();
         let mut label = DirectExecution::start(&script, "label").unwrap();
@@ -412,4 +1973,1161 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn forward_jump_to_label_block() {
+        let script = compile(
+            "start:\n    \"One\"\n    jump epilogue\n    \"Skipped\"\n    end\n\n\
+            epilogue:\n    \"Two\"\n    end\n",
+        );
+        let texts = collect_texts(&script, "start", &mut HashMap::new());
+        assert_eq!(texts, ["One", "Two"]);
+    }
+
+    #[test]
+    fn forward_jump_to_inline_label() {
+        let script = compile(
+            "start:\n    jump later\n    \"Skipped\"\n    later:\n    \"Reached\"\n    end\n",
+        );
+        let texts = collect_texts(&script, "start", &mut HashMap::new());
+        assert_eq!(texts, ["Reached"]);
+    }
+
+    #[test]
+    fn forward_jump_into_if_block() {
+        let script = compile(
+            "start:\n    jump inside\n    if flag then\n        \"Not this\"\n\
+            \x20       inside:\n        \"Inside\"\n    else\n        \"Else\"\n    endif\n\
+            \x20   \"After\"\n    end\n",
+        );
+        let mut env = HashMap::from([("flag".into(), Variant::Boolean(false))]);
+        let texts = collect_texts(&script, "start", &mut env);
+        assert_eq!(texts, ["Inside", "After"]);
+    }
+
+    #[test]
+    fn if_else_branches() {
+        let script = compile(
+            "start:\n    if flag then\n        \"If\"\n    else\n        \"Else\"\n    endif\n\
+            \x20   \"After\"\n    end\n",
+        );
+        for (flag, expected) in [(true, ["If", "After"]), (false, ["Else", "After"])] {
+            let mut env = HashMap::from([("flag".into(), Variant::Boolean(flag))]);
+            assert_eq!(collect_texts(&script, "start", &mut env), expected);
+        }
+    }
+
+    #[test]
+    fn if_without_else_is_skipped() {
+        let script = compile(
+            "start:\n    if flag then\n        \"One\"\n        \"Two\"\n    endif\n    \"After\"\n    end\n",
+        );
+        let mut env = HashMap::from([("flag".into(), Variant::Boolean(false))]);
+        assert_eq!(collect_texts(&script, "start", &mut env), ["After"]);
+        env.insert("flag".into(), Variant::Boolean(true));
+        assert_eq!(
+            collect_texts(&script, "start", &mut env),
+            ["One", "Two", "After"]
+        );
+    }
+
+    #[test]
+    fn else_if_chain_takes_every_branch() {
+        let source = read_to_string("./res/test.drs").unwrap();
+        let script = compile(&source);
+        for (weather, expected) in [
+            ("rain", "Take an umbrella."),
+            ("snow", "Wear something warm."),
+            ("sun", "Don't forget sunscreen!"),
+            ("fog", "I have no idea what's going on outside."),
+        ] {
+            let mut env = HashMap::from([("weather".into(), Variant::String(weather.into()))]);
+            assert_eq!(
+                collect_texts(&script, "weather_report", &mut env),
+                [expected]
+            );
+        }
+    }
+
+    #[test]
+    fn else_if_chain_without_else() {
+        let script = compile(
+            "start:\n    if n == 1 then\n        \"One\"\n    else if n == 2 then\n        \"Two\"\n\
+            \x20   endif\n    \"After\"\n    end\n",
+        );
+        for (n, expected) in [
+            (1, &["One", "After"][..]),
+            (2, &["Two", "After"]),
+            (3, &["After"]),
+        ] {
+            let mut env = HashMap::from([("n".into(), Variant::Int(n))]);
+            assert_eq!(collect_texts(&script, "start", &mut env), expected);
+        }
+    }
+
+    #[test]
+    fn nested_if_inside_else_if() {
+        let script = compile(
+            "start:\n    if a then\n        \"A\"\n    else if b then\n        if c then\n\
+            \x20           \"BC\"\n        else\n            \"B\"\n        endif\n    else\n\
+            \x20       \"None\"\n    endif\n    end\n",
+        );
+        for (a, b, c, expected) in [
+            (true, true, true, "A"),
+            (false, true, true, "BC"),
+            (false, true, false, "B"),
+            (false, false, true, "None"),
+        ] {
+            let mut env = HashMap::from([
+                ("a".into(), Variant::Boolean(a)),
+                ("b".into(), Variant::Boolean(b)),
+                ("c".into(), Variant::Boolean(c)),
+            ]);
+            assert_eq!(collect_texts(&script, "start", &mut env), [expected]);
+        }
+    }
+
+    #[test]
+    fn set_variables() {
+        let script = compile(
+            "start:\n    set flag = true\n    set name = \"Omori\"\n    set count = 3\n\
+            \x20   set copy = name\n    end\n",
+        );
+        let mut env = HashMap::new();
+        collect_texts(&script, "start", &mut env);
+        assert!(env["flag"] == Variant::Boolean(true));
+        assert!(env["name"] == Variant::String("Omori".into()));
+        assert!(env["count"] == Variant::Int(3));
+        assert!(env["copy"] == Variant::String("Omori".into()));
+    }
+
+    #[test]
+    fn add_and_sub_variables() {
+        let script = compile(
+            "start:\n    add gold 5\n    add gold 10\n    sub gold 3\n    sub debt gold\n    end\n",
+        );
+        let mut env = HashMap::new();
+        collect_texts(&script, "start", &mut env);
+        assert!(env["gold"] == Variant::Int(12));
+        assert!(env["debt"] == Variant::Int(-12));
+
+        env.insert("gold".into(), Variant::Int(i32::MAX));
+        collect_texts(&script, "start", &mut env);
+        assert!(env["gold"] == Variant::Int(i32::MAX - 3));
+    }
+
+    #[test]
+    fn flags_set_from_dialog() {
+        let source = read_to_string("./res/test.drs").unwrap();
+        let script = compile(&source);
+        let mut env = HashMap::new();
+        let texts = collect_texts(&script, "shop_keeper", &mut env);
+        assert_eq!(
+            texts,
+            [
+                "Oh, a new face!",
+                "Here, take some clams.",
+                "Now you have 5 of them. Lucky you."
+            ]
+        );
+        let texts = collect_texts(&script, "shop_keeper", &mut env);
+        assert_eq!(
+            texts,
+            [
+                "Welcome back!",
+                "Here, take some clams.",
+                "Now you have 10 of them. Lucky you."
+            ]
+        );
+        assert!(env["gold"] == Variant::Int(10));
+    }
+
+    fn evaluate(expression: &str, env: &mut HashMap<Rc<str>, Variant>) -> Variant {
+        let source = format!("start:\n    set result = {expression}\n    end\n");
+        let script = compile(&source);
+        env.remove("result");
+        collect_texts(&script, "start", env);
+        env.remove("result").unwrap()
+    }
+
+    #[test]
+    fn expression_precedence() {
+        let mut env = HashMap::new();
+        assert!(evaluate("1 + 2 * 3", &mut env) == Variant::Int(7));
+        assert!(evaluate("(1 + 2) * 3", &mut env) == Variant::Int(9));
+        assert!(evaluate("10 - 4 - 3", &mut env) == Variant::Int(3));
+        assert!(evaluate("17 % 5 * 2", &mut env) == Variant::Int(4));
+        assert!(evaluate("-2 * -3", &mut env) == Variant::Int(6));
+        assert!(evaluate("1 + 2 == 3", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("2 < 3 == true", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("not 1 == 2", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("true or false and false", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("(true or false) and false", &mut env) == Variant::Boolean(false));
+        assert!(evaluate("\"ab\" + \"cd\"", &mut env) == Variant::String("abcd".into()));
+    }
+
+    #[test]
+    fn expression_with_variables() {
+        let mut env = HashMap::from([
+            ("visits".into(), Variant::Int(3)),
+            ("has_key".into(), Variant::Boolean(false)),
+            ("name".into(), Variant::String("Basil".into())),
+        ]);
+        assert!(evaluate("visits > 2 and not has_key", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("visits >= 4 or has_key", &mut env) == Variant::Boolean(false));
+        assert!(evaluate("visits * visits - 1", &mut env) == Variant::Int(8));
+        assert!(evaluate("name + \"!\"", &mut env) == Variant::String("Basil!".into()));
+        // Absent variables
+        assert!(evaluate("missing < 1", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("not missing", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("missing == visits - visits", &mut env) == Variant::Boolean(false));
+        assert!(evaluate("missing + 1", &mut env) == Variant::Int(1));
+        // Division by zero
+        assert!(evaluate("visits / (visits - 3)", &mut env) == Variant::Int(0));
+        assert!(evaluate("visits % (3 - visits)", &mut env) == Variant::Int(0));
+    }
+
+    #[test]
+    fn complex_if_condition() {
+        let script = compile(
+            "start:\n    if visits > 2 and not has_key then\n        \"Again?\"\n\
+            \x20   else if (visits + 1) % 2 != 1 then\n        \"Odd\"\n    endif\n    end\n",
+        );
+        let mut env = HashMap::from([("visits".into(), Variant::Int(3))]);
+        assert_eq!(collect_texts(&script, "start", &mut env), ["Again?"]);
+        env.insert("has_key".into(), Variant::Boolean(true));
+        assert_eq!(collect_texts(&script, "start", &mut env), ["Odd"]);
+        env.insert("visits".into(), Variant::Int(2));
+        assert!(collect_texts(&script, "start", &mut env).is_empty());
+    }
+
+    #[test]
+    fn numeric_literals() {
+        let mut env = HashMap::new();
+        assert!(matches!(evaluate("0", &mut env), Variant::Int(0)));
+        assert!(matches!(evaluate("-5", &mut env), Variant::Int(-5)));
+        assert!(matches!(
+            evaluate("-2147483648", &mut env),
+            Variant::Int(i32::MIN)
+        ));
+        assert!(matches!(evaluate("0xFF", &mut env), Variant::Int(255)));
+        assert!(matches!(evaluate("-0x10", &mut env), Variant::Int(-16)));
+        assert!(matches!(evaluate("1.5", &mut env), Variant::Float(1.5)));
+        assert!(matches!(evaluate("-0.25", &mut env), Variant::Float(-0.25)));
+        // Minus after operand is still subtraction
+        assert!(matches!(evaluate("10 -5", &mut env), Variant::Int(5)));
+        assert!(matches!(evaluate("10 - -5", &mut env), Variant::Int(15)));
+    }
+
+    #[test]
+    fn int_and_float_mix() {
+        let mut env = HashMap::from([("speed".into(), Variant::Float(2.5))]);
+        assert!(matches!(evaluate("1 + 0.5", &mut env), Variant::Float(1.5)));
+        assert!(matches!(
+            evaluate("speed * 2", &mut env),
+            Variant::Float(5.0)
+        ));
+        assert!(matches!(evaluate("7 / 2", &mut env), Variant::Int(3)));
+        assert!(matches!(evaluate("7 / 2.0", &mut env), Variant::Float(3.5)));
+        assert!(matches!(
+            evaluate("speed / 0", &mut env),
+            Variant::Float(0.0)
+        ));
+        assert!(matches!(evaluate("-speed", &mut env), Variant::Float(-2.5)));
+        assert!(evaluate("1 == 1.0", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("2 != 2.5", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("speed > 2", &mut env) == Variant::Boolean(true));
+        assert!(evaluate("speed <= 2", &mut env) == Variant::Boolean(false));
+        assert!(Variant::Int(3) == Variant::Float(3.0));
+        assert!(Variant::Float(f64::NAN) != Variant::Float(f64::NAN));
+    }
+
+    #[test]
+    fn strings_in_arithmetic_are_zero() {
+        let mut env = HashMap::new();
+        env.insert("name".into(), Variant::String("Kel".into()));
+        env.insert("count".into(), Variant::String(" 3 ".into()));
+        assert!(matches!(evaluate("name + 1", &mut env), Variant::Int(1)));
+        assert!(matches!(evaluate("count * 2", &mut env), Variant::Int(6)));
+        assert!(matches!(
+            evaluate("name * 2.5", &mut env),
+            Variant::Float(0.0)
+        ));
+        // Two strings are joined instead
+        assert_eq!(evaluate("name + count", &mut env).to_string(), "Kel 3 ");
+
+        let script = compile("start:\n    add name 5\n    end\n");
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        exec.step(&mut env);
+        assert!(matches!(env["name"], Variant::Int(5)));
+    }
+
+    #[test]
+    fn add_float_to_int_variable() {
+        let script = compile("start:\n    add gold 0.5\n    sub gold 2\n    end\n");
+        let mut env = HashMap::from([("gold".into(), Variant::Int(1))]);
+        collect_texts(&script, "start", &mut env);
+        assert!(matches!(env["gold"], Variant::Float(-0.5)));
+    }
+
+    #[test]
+    fn variables_are_interpolated() {
+        let script = compile(
+            "start:\n    \"You have {gold} clams.\", \"{name}!\"; \"{{braces}}\"\n    end\n",
+        );
+        let mut env = HashMap::from([
+            ("gold".into(), Variant::Int(12)),
+            ("name".into(), Variant::String("Basil".into())),
+        ]);
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, stops, _, _) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "You have 12 clams. Basil!\n{braces}");
+        assert_eq!(positions(&stops), [19, 26, 34]);
+    }
+
+    #[test]
+    fn missing_variables_are_formatted() {
+        let script = compile("start:\n    \"Hi, {name}!\", \"Bye.\"\n    end\n");
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, stops, _, _) = exec.step(&mut HashMap::new()) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Hi, {name}! Bye.");
+        assert_eq!(positions(&stops), [12, 16]);
+
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        exec.set_missing_formatter(|name| format!("<{}>", name.to_uppercase()));
+        let ExecutionStep::Text(_, text, stops, _, _) = exec.step(&mut HashMap::new()) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Hi, <NAME>! Bye.");
+        assert_eq!(positions(&stops), [12, 16]);
+    }
+
+    #[test]
+    fn markup_is_split_into_styles() {
+        let script =
+            compile("start:\n    \"[b]{name}[/b], ~look~\", \"[pause=1]{gold} clams\"\n    end\n");
+        let mut env = HashMap::from([
+            ("gold".into(), Variant::Int(120)),
+            ("name".into(), Variant::String("Kel".into())),
+        ]);
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, stops, styles, _) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Kel, look 120 clams");
+        assert_eq!(positions(&stops), [10, 10, 19]);
+        assert_eq!(stops[1].delay, Some(1.0));
+        assert_eq!(
+            styles,
+            [
+                StyledSpan {
+                    range: 0..3,
+                    style: Style::Bold
+                },
+                StyledSpan {
+                    range: 5..9,
+                    style: Style::Wavy
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pauses_become_timed_stops() {
+        let script =
+            compile("start:\n    \"Well...[pause=0.5] fine[pause=1]\", \"Bye.\"\n    end\n");
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, stops, styles, _) = exec.step(&mut HashMap::new()) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Well... fine Bye.");
+        assert!(styles.is_empty());
+        assert_eq!(
+            stops,
+            [
+                Stop {
+                    position: 7,
+                    delay: Some(0.5)
+                },
+                Stop {
+                    position: 12,
+                    delay: Some(1.0)
+                },
+                Stop {
+                    position: 13,
+                    delay: None
+                },
+                Stop {
+                    position: 17,
+                    delay: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn speaker_is_resolved() {
+        let script = compile(
+            "define_character omori that\n    name -> \"OMORI\"\n    color -> \"#aabbcc\"\n    \
+            portrait -> neutral\n    blip -> \"omori_blip\"\nend_character\n\
+            define_character kel that\n    blip -> \"kel_blip\"\nend_character\n\
+            define_character hero that\nend_character\n\
+            define_portraits omori neutral sad\n\n\
+            start:\n    omori[sad] -> \"...\"\n    omori -> \"Hi.\"\n    kel -> \"Hey!\"\n    \
+            hero -> \"Hello.\"\n    end\n",
+        );
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let mut speakers = Vec::new();
+        while let ExecutionStep::Text(who, _, _, _, _) = exec.step(&mut env) {
+            speakers.push(who.expect("Every line has a speaker"));
+        }
+        let [sad, neutral, kel, hero] = speakers.as_slice() else {
+            panic!("Expected four lines");
+        };
+        assert_eq!(sad.id, "omori".into());
+        assert_eq!(sad.portrait, Some("sad".into()));
+        assert_eq!(sad.character.name.as_str(), "OMORI");
+        assert_eq!(sad.character.color.as_ref().unwrap().as_str(), "#aabbcc");
+        assert_eq!(sad.character.blip.as_ref().unwrap().as_str(), "omori_blip");
+        // Line without portrait shows the default one
+        assert_eq!(neutral.portrait, Some("neutral".into()));
+        assert_eq!(kel.character.name.as_str(), "kel");
+        assert_eq!(kel.portrait, None);
+        // Character declared without fields has only its id
+        assert_eq!(hero.character.name.as_str(), "hero");
+        assert!(hero.character.color.is_none());
+        assert!(hero.character.blip.is_none());
+    }
+
+    #[test]
+    fn tags_reach_steps() {
+        let script = compile(
+            "start:\n    \"Hi.\" # @voice:hi_01 @shake\n    choice answer yes_no # @timer:5\n    \
+            \"Bye.\"\n    end\n\n\
+            define_choice yes_no that\n    yes -> \"Yes\"\nend_choice\n",
+        );
+        let tags = |tags: &Tags| -> Vec<(String, Option<String>)> {
+            tags.iter()
+                .map(|(key, value)| {
+                    let value = value.as_ref().map(|value| value.as_str().to_owned());
+                    (key.as_str().to_owned(), value)
+                })
+                .collect()
+        };
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, _, _, _, line_tags) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(
+            tags(&line_tags),
+            [
+                ("voice".to_owned(), Some("hi_01".to_owned())),
+                ("shake".to_owned(), None)
+            ]
+        );
+        let ExecutionStep::Choice(_, _, choice_tags) = exec.step(&mut env) else {
+            panic!("Expected choice");
+        };
+        assert_eq!(
+            tags(&choice_tags),
+            [("timer".to_owned(), Some("5".to_owned()))]
+        );
+        exec.answer(&mut env, "yes").unwrap();
+        let ExecutionStep::Text(_, _, _, _, line_tags) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert!(line_tags.is_empty());
+    }
+
+    #[test]
+    fn wait_is_yielded() {
+        let script = compile("start:\n    \"...\"\n    wait 1.5\n    \"Hello?\"\n    end\n");
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        assert!(matches!(exec.step(&mut env), ExecutionStep::Text(..)));
+        assert!(matches!(exec.step(&mut env), ExecutionStep::Wait(1.5)));
+        assert!(matches!(exec.step(&mut env), ExecutionStep::Text(..)));
+        assert!(matches!(exec.step(&mut env), ExecutionStep::End));
+    }
+
+    #[test]
+    fn stops_are_counted_in_characters() {
+        let script = compile(
+            "start:\n    \"\", \"おはよう、{name}。\", \"~ゆめ~\", \"1\", \"2\", \"3\", \"4\", \"5\", \"6\", \
+            \"7\", \"8\", \"9\", \"10\", \"11\"\n    end\n",
+        );
+        let mut env = HashMap::from([("name".into(), Variant::String("オモリ".into()))]);
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, stops, styles, _) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(
+            text.as_str(),
+            " おはよう、オモリ。 ゆめ 1 2 3 4 5 6 7 8 9 10 11"
+        );
+        assert_eq!(
+            positions(&stops),
+            [1, 11, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32, 35, 37]
+        );
+        assert_eq!(styles[0].range, 29..35);
+        assert_eq!(&text.as_str()[styles[0].range.clone()], "ゆめ");
+    }
+
+    #[test]
+    fn stops_are_counted_after_decoding() {
+        let script = compile("start:\n    \"\\u00e9t\\u00e9\\n\", \"\\\"Hi\\\"\"\n    end\n");
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, stops, _, _) = exec.step(&mut HashMap::new()) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "été\n \"Hi\"");
+        assert_eq!(positions(&stops), [5, 9]);
+    }
+
+    #[test]
+    fn visits_are_counted() {
+        let script = compile(
+            "start:\n    \"Start\"\n    again:\n    add i 1\n    if i < 3 then\n        \
+            jump again\n    endif\n    if visited(again) and not visited(never) then\n        \
+            \"{i}\"\n    endif\n    end\n\nnever:\n    \"Never\"\n    \
+            if visits(start) + visits(never) == 0 then\n        end\n    endif\n    end\n",
+        );
+        let mut env = HashMap::new();
+        assert_eq!(collect_texts(&script, "start", &mut env), ["Start", "3"]);
+        assert!(env["@visits:start"] == Variant::Int(1));
+        assert!(env["@visits:again"] == Variant::Int(3));
+        assert!(!env.contains_key("@visits:never"));
+    }
+
+    #[test]
+    fn once_runs_on_first_visit() {
+        let script = compile(
+            "start:\n    once\n        \"Nice to meet you!\"\n    endonce\n    \"Hi.\"\n    \
+            again:\n    once\n        \"Once more?\"\n        jump again\n    endonce\n    end\n",
+        );
+        let mut env = HashMap::new();
+        let texts = collect_texts(&script, "start", &mut env);
+        assert_eq!(texts, ["Nice to meet you!", "Hi.", "Once more?"]);
+        // Counters live in the environment, so saved ones are picked up again
+        let mut saved = env.clone();
+        assert_eq!(collect_texts(&script, "start", &mut saved), ["Hi."]);
+        assert!(saved["@visits:start"] == Variant::Int(2));
+    }
+
+    #[test]
+    fn once_after_branch_belongs_to_enclosing_label() {
+        let script = compile(
+            "talk:\n    if false then\n        extra:\n        \"Extra\"\n    endif\n    \
+            once\n        \"Hello\"\n    endonce\n    \"Bye\"\n    end\n",
+        );
+        let mut env = HashMap::new();
+        assert_eq!(collect_texts(&script, "talk", &mut env), ["Hello", "Bye"]);
+        assert_eq!(collect_texts(&script, "talk", &mut env), ["Bye"]);
+        assert!(!env.contains_key("@visits:extra"));
+    }
+
+    #[test]
+    fn translation_replaces_texts() {
+        let mut script = DirectScript::try_from(
+            parse_to_ast(
+                "define_choice yes_no that\n    yes -> \"Yes\"\n    no -> \"No\"\nend_choice\n\n\
+                start:\n    \"Hello.\", \"[b]How[/b] are you?\" #id:greeting\n    \
+                \"Untranslated.\"\n    choice answer yes_no\n    end\n",
+            )
+            .unwrap()
+            .as_slice(),
+        )
+        .unwrap();
+        let po = "msgctxt \"greeting\"\nmsgid \"\"\nmsgstr \"[b]Ça va[/b][pause=0.5] ?[stop]Bonjour.\"\n\n\
+            msgctxt \"yes_no.yes\"\nmsgid \"Yes\"\nmsgstr \"Oui\"\n";
+        script
+            .set_translation(Some(&Translation::from_po(po).unwrap()))
+            .unwrap();
+        let script = Rc::new(script);
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, stops, styles, _) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        // Stops and styles come from the translation, not from the source
+        assert_eq!(text.as_str(), "Ça va ?Bonjour.");
+        assert_eq!(
+            stops,
+            [
+                Stop {
+                    position: 5,
+                    delay: Some(0.5)
+                },
+                Stop {
+                    position: 7,
+                    delay: None
+                },
+                Stop {
+                    position: 15,
+                    delay: None
+                },
+            ]
+        );
+        assert_eq!(styles[0].range, 0..6);
+        let ExecutionStep::Text(_, text, ..) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Untranslated.");
+        let ExecutionStep::Choice(_, options, _) = exec.step(&mut env) else {
+            panic!("Expected choice");
+        };
+        assert_eq!(options[0].1.as_str(), "Oui");
+        assert_eq!(options[1].1.as_str(), "No");
+
+        // Translation can be changed only while nothing runs the script
+        drop(exec);
+        let mut script = Rc::into_inner(script).unwrap();
+        let po = "msgctxt \"greeting\"\nmsgid \"\"\nmsgstr \"[b]Oops\"\n";
+        let error = script
+            .set_translation(Some(&Translation::from_po(po).unwrap()))
+            .unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadTranslation(_)));
+        script.set_translation(None).unwrap();
+        let script = Rc::new(script);
+        let texts = collect_texts(
+            &script,
+            "start",
+            &mut HashMap::from([("answer".into(), Variant::String("yes".into()))]),
+        );
+        assert_eq!(texts, ["Hello. How are you?", "Untranslated."]);
+    }
+
+    #[test]
+    fn call_returns_back() {
+        let script = compile(
+            "start:\n    call greeting\n    \"Middle\"\n    call greeting\n    end\n\n\
+            greeting:\n    \"Hello\"\n    call name\n    return\n\n\
+            name:\n    \"I'm Mari\"\n    return\n",
+        );
+        let texts = collect_texts(&script, "start", &mut HashMap::new());
+        assert_eq!(texts, ["Hello", "I'm Mari", "Middle", "Hello", "I'm Mari"]);
+        // Started right at the called label, `return` ends the dialog
+        let texts = collect_texts(&script, "greeting", &mut HashMap::new());
+        assert_eq!(texts, ["Hello", "I'm Mari"]);
+    }
+
+    #[test]
+    fn endless_recursion_is_stopped() {
+        let script = compile("start:\n    add depth 1\n    call start\n    return\n");
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Error(error) = exec.step(&mut env) else {
+            panic!("Expected error");
+        };
+        assert_eq!(error, RuntimeError::CallTooDeep);
+        assert!(env["depth"] == Variant::Int(MAX_CALL_DEPTH as i32 + 1));
+        // Broken execution doesn't go on
+        assert!(matches!(exec.step(&mut env), ExecutionStep::Error(_)));
+        assert!(env["depth"] == Variant::Int(MAX_CALL_DEPTH as i32 + 1));
+    }
+
+    #[test]
+    fn choice_options_depend_on_conditions() {
+        let script = compile(
+            "define_choice fruits that\n    apple -> \"Apple\"\n    \
+            banana -> \"Banana\" if visited_jungle\n    \
+            money -> \"Money\" if gold > 10 and not broke else disabled\n\
+            end_choice\n\n\
+            start:\n    choice answer fruits\n    end\n",
+        );
+        let options = |env: &mut HashMap<Rc<str>, Variant>| {
+            let mut exec = DirectExecution::start(&script, "start").unwrap();
+            let ExecutionStep::Choice(store_to, options, _) = exec.step(env) else {
+                panic!("Expected choice");
+            };
+            assert_eq!(store_to.as_str(), "answer");
+            options
+                .into_iter()
+                .map(|(name, _, enabled)| (name.as_str().to_owned(), enabled))
+                .collect::<Vec<_>>()
+        };
+        let mut env = HashMap::new();
+        assert_eq!(
+            options(&mut env),
+            [("apple".into(), true), ("money".into(), false)]
+        );
+        env.insert("visited_jungle".into(), Variant::Boolean(true));
+        env.insert("gold".into(), Variant::Int(20));
+        assert_eq!(
+            options(&mut env),
+            [
+                ("apple".into(), true),
+                ("banana".into(), true),
+                ("money".into(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn menu_runs_picked_option() {
+        let source = read_to_string("./res/test.drs").unwrap();
+        let script = compile(&source);
+        let mut env = HashMap::from([("answer".into(), Variant::String("orange".into()))]);
+        let texts = collect_texts(&script, "test_menu", &mut env);
+        assert_eq!(
+            texts,
+            [
+                "What do you like the most? Apples or oranges?",
+                "EWWWW-W disgusting!",
+                "Anyway, go away!"
+            ]
+        );
+        // Hidden option can't be picked until it is shown
+        env.insert("answer".into(), Variant::String("nothing".into()));
+        env.insert("gold".into(), Variant::Int(120));
+        let texts = collect_texts(&script, "test_menu", &mut env);
+        assert_eq!(
+            texts,
+            [
+                "What do you like the most? Apples or oranges?",
+                "Anyway, go away!"
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_menus() {
+        let script = compile(
+            "start:\n    menu first\n        a -> \"A\"\n            menu second\n                \
+            b -> \"B\"\n                    \"AB\"\n                c -> \"C\"\n            endmenu\n            \
+            \"A!\"\n        d -> \"D\"\n            if first == \"d\" then\n                \"D\"\n            \
+            endif\n    endmenu\n    end\n",
+        );
+        let mut env = HashMap::from([
+            ("first".into(), Variant::String("a".into())),
+            ("second".into(), Variant::String("b".into())),
+        ]);
+        assert_eq!(collect_texts(&script, "start", &mut env), ["AB", "A!"]);
+        env.insert("second".into(), Variant::String("c".into()));
+        assert_eq!(collect_texts(&script, "start", &mut env), ["A!"]);
+        env.insert("first".into(), Variant::String("d".into()));
+        assert_eq!(collect_texts(&script, "start", &mut env), ["D"]);
+
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Choice(store_to, options, _) = exec.step(&mut env) else {
+            panic!("Expected choice");
+        };
+        assert_eq!(store_to.as_str(), "first");
+        assert_eq!(options.len(), 2);
+    }
+
+    #[test]
+    fn choice_waits_for_answer() {
+        let script = compile(
+            "define_choice doors that\n    left -> \"Left\"\n    \
+            right -> \"Right\" if has_key else disabled\n    \
+            secret -> \"Secret\" if false\nend_choice\n\n\
+            start:\n    choice door doors\n    \"Opened {door} door\"\n    end\n",
+        );
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        assert_eq!(exec.answer(&mut env, 0).unwrap_err(), AnswerError::NotAsked);
+        assert!(matches!(exec.step(&mut env), ExecutionStep::Choice(..)));
+        assert!(exec.is_waiting_answer());
+        // Without an answer the same choice is offered again
+        let ExecutionStep::Choice(_, options, _) = exec.step(&mut env) else {
+            panic!("Expected choice");
+        };
+        assert_eq!(options.len(), 2);
+
+        assert_eq!(
+            exec.answer(&mut env, 2).unwrap_err(),
+            AnswerError::OutOfRange(2)
+        );
+        assert_eq!(
+            exec.answer(&mut env, "secret").unwrap_err(),
+            AnswerError::Unknown("secret".into())
+        );
+        assert_eq!(
+            exec.answer(&mut env, "right").unwrap_err(),
+            AnswerError::Disabled("right".into())
+        );
+        assert!(!env.contains_key("door"));
+
+        exec.answer(&mut env, 0).unwrap();
+        assert!(!exec.is_waiting_answer());
+        assert!(env["door"] == Variant::String("left".into()));
+        let ExecutionStep::Text(_, text, ..) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Opened left door");
+    }
+
+    #[test]
+    fn choice_without_options_is_skipped() {
+        let script = compile(
+            "define_choice yes_no that\n    yes -> \"Yes\" if false\n    \
+            no -> \"No\" if asked else disabled\nend_choice\n\n\
+            start:\n    choice answer yes_no\n    \"After\"\n    \
+            menu again\n        yes -> \"Yes\" if false\n            \"Picked\"\n    endmenu\n    \
+            \"End\"\n    end\n",
+        );
+        // Old answer of the menu doesn't run its branch
+        let mut env = HashMap::from([("again".into(), Variant::String("yes".into()))]);
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Text(_, text, ..) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "After");
+        assert!(!exec.is_waiting_answer());
+        assert!(!env.contains_key("answer"));
+        let ExecutionStep::Text(_, text, ..) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "End");
+        assert!(matches!(exec.step(&mut env), ExecutionStep::End));
+    }
+
+    #[test]
+    fn trigger_arguments_and_result() {
+        let script = compile(
+            "start:\n    trigger shake 3 \"strong\" door_02 (power * 2) -1.5 true\n    \
+            trigger roll_dice 6 -> result\n    \"Rolled {result}\"\n    end\n",
+        );
+        let mut env = HashMap::from([("power".into(), Variant::Int(5))]);
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let ExecutionStep::Trigger(what, arguments, store_to) = exec.step(&mut env) else {
+            panic!("Expected trigger");
+        };
+        assert_eq!(what.as_str(), "shake");
+        assert_eq!(
+            arguments,
+            [
+                Variant::Int(3),
+                Variant::String("strong".into()),
+                Variant::String("door_02".into()),
+                Variant::Int(10),
+                Variant::Float(-1.5),
+                Variant::Boolean(true),
+            ]
+        );
+        assert!(store_to.is_none());
+        assert!(!exec.is_waiting_result());
+
+        let ExecutionStep::Trigger(what, _, store_to) = exec.step(&mut env) else {
+            panic!("Expected trigger");
+        };
+        assert_eq!(what.as_str(), "roll_dice");
+        assert_eq!(store_to.unwrap().as_str(), "result");
+        // Execution doesn't go on without the result
+        assert!(matches!(exec.step(&mut env), ExecutionStep::Trigger(..)));
+        exec.resume(&mut env, Variant::Int(4)).unwrap();
+        assert_eq!(
+            exec.resume(&mut env, Variant::Int(5)).unwrap_err(),
+            AnswerError::NotAsked
+        );
+        let ExecutionStep::Text(_, text, ..) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Rolled 4");
+    }
+
+    /// Runs execution from the seed until the end, collecting all shown texts.
+    fn run_seeded(script: &Rc<DirectScript>, seed: u64) -> Vec<String> {
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(script, "start").unwrap();
+        exec.set_seed(seed);
+        assert_eq!(exec.seed(), seed);
+        let mut texts = Vec::new();
+        loop {
+            match exec.step(&mut env) {
+                ExecutionStep::Text(_, text, ..) => texts.push(text.as_str().to_owned()),
+                ExecutionStep::End => return texts,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn random_branches_follow_weights() {
+        let script = compile(
+            "start:\n    random 3\n        \"common\"\n    or\n        \"rare\"\n    endrandom\n    end\n",
+        );
+        let runs = 4000;
+        let common = (0..runs)
+            .filter(|seed| run_seeded(&script, *seed) == ["common"])
+            .count();
+        // Expected 3000, deviation is about 27
+        assert!((2850..3150).contains(&common), "Got {common} of {runs}");
+    }
+
+    #[test]
+    fn random_run_replays_from_seed() {
+        let script = compile(
+            "start:\n    set i = 0\n    again:\n    random\n        \"A\"\n    or 2\n        \"B\"\n    \
+            or\n    endrandom\n    set roll = random(1, 100)\n    \"{roll}\"\n    add i 1\n    \
+            if i < 20 then\n        jump again\n    endif\n    end\n",
+        );
+        let first = run_seeded(&script, 1234);
+        assert_eq!(first, run_seeded(&script, 1234));
+        assert_ne!(first, run_seeded(&script, 4321));
+        // Empty branch shows nothing, so only rolls are always there
+        let rolls: Vec<i32> = first.iter().filter_map(|text| text.parse().ok()).collect();
+        assert_eq!(rolls.len(), 20);
+        assert!(rolls.iter().all(|roll| (1..=100).contains(roll)));
+        assert!(first.len() > 20);
+    }
+
+    #[test]
+    fn random_expression_stays_in_range() {
+        let script = compile(
+            "start:\n    set die = random(6, 1)\n    set chance = random(0.5, max)\n    \
+            \"{die} {chance}\"\n    end\n",
+        );
+        for seed in 0..200 {
+            let texts = run_seeded(&script, seed);
+            let (die, chance) = texts[0].split_once(' ').unwrap();
+            assert!((1..=6).contains(&die.parse::<i32>().unwrap()));
+            // Absent `max` is zero, so the range goes down
+            let chance: f64 = chance.parse().unwrap();
+            assert!(chance > 0.0 && chance <= 0.5, "Got {chance}");
+        }
+    }
+
+    #[test]
+    fn compiled_script_round_trips() {
+        let source = read_to_string("./res/test.drs").unwrap();
+        let script = compile(&source);
+        let bytes = script.to_bytes();
+        let loaded = Rc::new(DirectScript::from_bytes(&bytes).unwrap());
+        assert_eq!(loaded.to_bytes(), bytes);
+        let sources = |script: &DirectScript| -> Vec<(String, String)> {
+            script
+                .sources()
+                .map(|(id, text)| (id, text.as_str().to_owned()))
+                .collect()
+        };
+        assert_eq!(sources(&loaded), sources(&script));
+        let labels = [
+            "label",
+            "show_variables",
+            "weather_report",
+            "test_choice",
+            "test_menu",
+            "shop_keeper",
+        ];
+        for label in labels {
+            let mut env = HashMap::new();
+            env.insert("answer".into(), Variant::String("apple".into()));
+            let expected = collect_texts(&script, label, &mut env.clone());
+            assert_eq!(collect_texts(&loaded, label, &mut env), expected);
+        }
+    }
+
+    #[test]
+    fn bad_compiled_script_is_rejected() {
+        let bytes = compile("start:\n    \"Hi.\"\n    end\n").to_bytes();
+        let message = |bytes: &[u8]| match DirectScript::from_bytes(bytes).unwrap_err().kind() {
+            ErrorKind::BadCompiledScript(message) => message.clone(),
+            kind => panic!("Unexpected error: {kind}"),
+        };
+        assert_eq!(message(b"start:"), "not a compiled dialog script");
+        // Format version follows the magic
+        let mut outdated = bytes.clone();
+        outdated[4] += 1;
+        assert!(message(&outdated).contains("has to be compiled again"));
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+        assert!(message(&corrupted).contains("checksum"));
+        assert!(message(&bytes[..bytes.len() - 1]).contains("checksum"));
+    }
+
+    #[test]
+    fn snapshot_continues_execution() {
+        let source = "start:\n    \"A\"\n    call ask\n    \"{roll} {answer}\"\n    end\n\n\
+            ask:\n    choice answer yes_no\n    set roll = random(1, 1000000)\n    return\n\n\
+            define_choice yes_no that\n    yes -> \"Yes\"\n    no -> \"No\" if false\nend_choice\n";
+        let script = compile(source);
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        exec.set_seed(42);
+        exec.step(&mut env);
+        let ExecutionStep::Choice(_, offered, _) = exec.step(&mut env) else {
+            panic!("Expected choice");
+        };
+        let snapshot = exec.snapshot();
+
+        // Another copy of the same script, like after restart of the game
+        let mut other = compile(source);
+        let po = "msgctxt \"yes_no.yes\"\nmsgid \"Yes\"\nmsgstr \"Oui\"\n";
+        let translation = Translation::from_po(po).unwrap();
+        Rc::get_mut(&mut other)
+            .unwrap()
+            .set_translation(Some(&translation))
+            .unwrap();
+        let mut restored = DirectExecution::restore(&other, &snapshot).unwrap();
+        assert!(restored.is_waiting_answer());
+        assert_eq!(restored.seed(), 42);
+        let ExecutionStep::Choice(store_to, restored_offered, _) = restored.step(&mut env) else {
+            panic!("Expected choice");
+        };
+        assert_eq!(store_to.as_str(), "answer");
+        // Hidden option stays hidden, texts follow the current translation
+        assert_eq!(offered.len(), 1);
+        assert_eq!(restored_offered.len(), 1);
+        assert_eq!(restored_offered[0].1.as_str(), "Oui");
+
+        let mut restored_env = env.clone();
+        exec.answer(&mut env, "yes").unwrap();
+        restored.answer(&mut restored_env, "yes").unwrap();
+        // Return goes back to the caller and random picks go on the same way
+        let ExecutionStep::Text(_, expected, ..) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        let ExecutionStep::Text(_, text, ..) = restored.step(&mut restored_env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), expected.as_str());
+        assert!(matches!(
+            restored.step(&mut restored_env),
+            ExecutionStep::End
+        ));
+    }
+
+    #[test]
+    fn snapshot_shows_the_last_text_again() {
+        let script = compile(
+            "start:\n    \"A\"\n    who -> \"B, {name}\" # @voice:b\n    \"C\"\n    end\n\n\
+            define_character who that\n    name -> \"Who\"\nend_character\n",
+        );
+        let mut env = HashMap::from([("name".into(), Variant::String("Kel".into()))]);
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        exec.step(&mut env);
+        let ExecutionStep::Text(_, shown, ..) = exec.step(&mut env) else {
+            panic!("Expected text");
+        };
+        let mut restored = DirectExecution::restore(&script, &exec.snapshot()).unwrap();
+        let ExecutionStep::Text(who, text, _, _, tags) = restored.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), shown.as_str());
+        assert_eq!(who.unwrap().id.as_str(), "who");
+        assert_eq!(tags[0].0.as_str(), "voice");
+        // Snapshot of restored execution before it goes on is the same
+        let mut again = DirectExecution::restore(&script, &restored.snapshot()).unwrap();
+        assert!(matches!(again.step(&mut env), ExecutionStep::Text(..)));
+        let ExecutionStep::Text(_, text, ..) = restored.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "C");
+    }
+
+    #[test]
+    fn snapshot_keeps_pending_trigger() {
+        let script =
+            compile("start:\n    trigger ask_name 1 \"a\" -> name\n    \"{name}\"\n    end\n");
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        exec.step(&mut env);
+        let mut restored = DirectExecution::restore(&script, &exec.snapshot()).unwrap();
+        let ExecutionStep::Trigger(what, args, store_to) = restored.step(&mut env) else {
+            panic!("Expected trigger");
+        };
+        assert_eq!(what.as_str(), "ask_name");
+        assert!(matches!(args.as_slice(), [Variant::Int(1), Variant::String(a)] if &**a == "a"));
+        assert_eq!(store_to.unwrap().as_str(), "name");
+        restored
+            .resume(&mut env, Variant::String("Kel".into()))
+            .unwrap();
+        let ExecutionStep::Text(_, text, ..) = restored.step(&mut env) else {
+            panic!("Expected text");
+        };
+        assert_eq!(text.as_str(), "Kel");
+    }
+
+    #[test]
+    fn snapshot_of_other_script_is_rejected() {
+        let script = compile("start:\n    \"A\"\n    \"B\"\n    end\n");
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        exec.step(&mut HashMap::new());
+        let snapshot = exec.snapshot();
+        let changed = compile("start:\n    \"A\"\n    \"C\"\n    end\n");
+        let error = DirectExecution::restore(&changed, &snapshot).err().unwrap();
+        assert_eq!(error.kind(), &ErrorKind::ScriptChanged);
+
+        let mut corrupted = snapshot.clone();
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+        let error = DirectExecution::restore(&script, &corrupted).err().unwrap();
+        assert!(matches!(error.kind(), ErrorKind::BadSnapshot(_)));
+        // Compiled script isn't a snapshot
+        let error = DirectExecution::restore(&script, &script.to_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::BadSnapshot("not a dialog snapshot".into())
+        );
+    }
+
+    #[test]
+    fn compiled_script_with_bad_references_is_rejected() {
+        let message = |change: fn(&mut DirectScript)| {
+            let ast = parse_to_ast("start:\n    set a = 1\n    \"Hi.\"\n    end\n").unwrap();
+            let mut script = DirectScript::try_from(ast.as_slice()).unwrap();
+            change(&mut script);
+            // Checksum of such file is right, only its content is wrong
+            match DirectScript::from_bytes(&script.to_bytes())
+                .unwrap_err()
+                .kind()
+            {
+                ErrorKind::BadCompiledScript(message) => message.clone(),
+                kind => panic!("Unexpected error: {kind}"),
+            }
+        };
+        assert_eq!(
+            message(|script| script.code[0] = Command::Jump(99)),
+            "jump to #99 is out of range"
+        );
+        assert_eq!(
+            message(|script| script.code[0] = Command::Visit(99)),
+            "string #99 is out of range"
+        );
+        assert_eq!(
+            message(|script| script.labels[0].1 = 99),
+            "jump to #99 is out of range"
+        );
+        assert_eq!(
+            message(|script| {
+                let Command::Text(_, _, what, _) = &mut script.code[2] else {
+                    panic!("Expected text");
+                };
+                *what = 5;
+            }),
+            "line #5 is out of range"
+        );
+        assert_eq!(
+            message(|script| {
+                let mut value = Expression::Var(Variable::Int(1));
+                for _ in 0..MAX_EXPRESSION_DEPTH {
+                    value = Expression::Unary(UnaryOperation::Negate, Box::new(value));
+                }
+                script.code[1] = Command::Set(1, value);
+            }),
+            format!("expression is nested deeper than {MAX_EXPRESSION_DEPTH} levels")
+        );
+    }
+
+    #[test]
+    fn deepest_parsed_expression_can_be_loaded() {
+        // Every `-` is a level, and so is `n` at the bottom
+        let source =
+            |negations| format!("start:\n    set a = {}n\n    end\n", "- ".repeat(negations));
+        let script = compile(&source(MAX_EXPRESSION_DEPTH - 1));
+        assert!(DirectScript::from_bytes(&script.to_bytes()).is_ok());
+
+        let error = parse_to_ast(&source(MAX_EXPRESSION_DEPTH)).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ExpressionTooDeep);
+        assert_eq!(error.location().unwrap().column, 13);
+    }
 }
@@ -1,16 +1,29 @@
+mod binary;
+mod error;
 mod grammar;
 mod interpreter;
+mod loader;
+mod locale;
+mod markup;
+mod random;
+mod template;
 mod utils;
 
 // Re-exports
 pub mod ast {
+    pub use crate::error::{DialogError, ErrorKind, Location};
     pub use crate::grammar::{
-        AstNode, Command, Condition, Identifier, LogicOperation, Rule, Text, Variable, parse_to_ast,
+        AstNode, BinaryOperation, Character, Command, Expression, Identifier, OptionCondition,
+        Rule, Text, UnaryOperation, Variable, parse_to_ast,
     };
 }
 
 pub mod exec {
     pub use crate::interpreter::{
-        DirectExecution, DirectScript, Environment, ExecutionStep, Variant,
+        Answer, AnswerError, DirectExecution, DirectScript, Environment, ExecutionStep,
+        MAX_CALL_DEPTH, RuntimeError, Speaker, Stop, Tags, Variant,
     };
+    pub use crate::loader::{load_project, load_project_with};
+    pub use crate::locale::{Translation, extract_po};
+    pub use crate::markup::{Style, StyledSpan};
 }
@@ -0,0 +1,536 @@
+use std::{
+    fs::read_to_string,
+    io,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    error::{DialogError, ErrorKind},
+    grammar::{
+        AstNode, Command, Expression, Identifier, OptionCondition, ParserContext, parse_module,
+        report_first,
+    },
+    interpreter::DirectScript,
+    template::is_valid_name,
+};
+
+/// Parsed file of a project.
+struct Module {
+    path: Rc<Path>,
+    /// File name that prefixes its labels and choices, absent for the root file
+    namespace: Option<String>,
+    ast: Vec<AstNode>,
+    context: ParserContext,
+    /// Namespaces of files imported by this one
+    imported: Vec<String>,
+}
+
+struct Loader<F> {
+    read: F,
+    /// Files in order of loading, every file comes after files it imports
+    modules: Vec<Module>,
+    /// Files being loaded right now, used to find import cycles
+    stack: Vec<(Rc<Path>, Option<String>)>,
+}
+
+/// Loads script from the root file and every file it imports.
+/// Imports are relative to the importing file, labels and choices of imported
+/// file are referred to with its name: `import "town.drs"` gives `town.greeting`.
+pub fn load_project(root: impl AsRef<Path>) -> Result<DirectScript, DialogError> {
+    load_project_with(root, |path| read_to_string(path))
+}
+
+/// Same as [`load_project`], but files are read with `read`.
+pub fn load_project_with(
+    root: impl AsRef<Path>,
+    read: impl FnMut(&Path) -> io::Result<String>,
+) -> Result<DirectScript, DialogError> {
+    let root: Rc<Path> = normalize(root.as_ref()).into();
+    let mut loader = Loader {
+        read,
+        modules: Vec::new(),
+        stack: Vec::new(),
+    };
+    let source = (loader.read)(&root).map_err(|err| {
+        let message = format!("can't read `{}`: {err}", root.display());
+        DialogError::new(ErrorKind::BadImport(message), None)
+    })?;
+    loader.load(root.clone(), None, &source)?;
+    link(loader.modules).map(|script| script.with_source(root))
+}
+
+impl<F: FnMut(&Path) -> io::Result<String>> Loader<F> {
+    fn load(
+        &mut self,
+        path: Rc<Path>,
+        namespace: Option<String>,
+        source: &str,
+    ) -> Result<(), DialogError> {
+        let (ast, context) = parse_module(source).map_err(|err| err.in_file(&path))?;
+        self.stack.push((path.clone(), namespace.clone()));
+        let mut imported = Vec::new();
+        for (import, location) in &context.imports {
+            let fail = |kind| DialogError::new(kind, Some(location.clone())).in_file(&path);
+            let directory = path.parent().unwrap_or(Path::new(""));
+            let import_path: Rc<Path> = normalize(&directory.join(import.as_str())).into();
+            if let Some(start) = self.stack.iter().position(|(item, _)| *item == import_path) {
+                let chain = self.stack[start..]
+                    .iter()
+                    .map(|(item, _)| item)
+                    .chain([&import_path])
+                    .map(|item| format!("`{}`", item.display()))
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                return Err(fail(ErrorKind::ImportCycle(chain)));
+            }
+            let import_namespace = import_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|stem| is_valid_name(stem))
+                .ok_or_else(|| {
+                    let message = format!("name of `{}` can't be a namespace", import.as_str());
+                    fail(ErrorKind::BadImport(message))
+                })?
+                .to_owned();
+            let taken_by = self
+                .modules
+                .iter()
+                .map(|module| (&module.path, &module.namespace))
+                .chain(self.stack.iter().map(|(path, namespace)| (path, namespace)))
+                .find(|(_, namespace)| namespace.as_ref() == Some(&import_namespace))
+                .map(|(path, _)| path.clone());
+            match taken_by {
+                // Already loaded through another import
+                Some(taken_by) if taken_by == import_path => {}
+                Some(taken_by) => {
+                    let message = format!(
+                        "namespace `{import_namespace}` is already taken by `{}`",
+                        taken_by.display()
+                    );
+                    return Err(fail(ErrorKind::BadImport(message)));
+                }
+                None => {
+                    let source = (self.read)(&import_path).map_err(|err| {
+                        let message = format!("can't read `{}`: {err}", import_path.display());
+                        fail(ErrorKind::BadImport(message))
+                    })?;
+                    self.load(import_path, Some(import_namespace.clone()), &source)?;
+                }
+            }
+            imported.push(import_namespace);
+        }
+        self.stack.pop();
+        self.modules.push(Module {
+            path,
+            namespace,
+            ast,
+            context,
+            imported,
+        });
+        Ok(())
+    }
+}
+
+/// Checks references between files and builds one script out of all of them.
+fn link(mut modules: Vec<Module>) -> Result<DirectScript, DialogError> {
+    let qualify_all = |module: &Module, idents: &[Identifier]| -> Vec<Identifier> {
+        let namespace = module.namespace.as_deref();
+        idents
+            .iter()
+            .map(|ident| qualify(ident, namespace))
+            .collect()
+    };
+    let labels: Vec<_> = modules
+        .iter()
+        .flat_map(|module| qualify_all(module, &module.context.decl_labels))
+        .collect();
+    let choices: Vec<_> = modules
+        .iter()
+        .flat_map(|module| qualify_all(module, &module.context.decl_choices))
+        .collect();
+    let called: Vec<_> = modules
+        .iter()
+        .flat_map(|module| qualify_all(module, &module.context.called_labels))
+        .collect();
+    // Characters aren't namespaced, they are shared by the whole project
+    let mut characters: Vec<&Identifier> = Vec::new();
+    for module in &modules {
+        for (who, location) in &module.context.decl_characters {
+            if characters.contains(&who) {
+                let kind = ErrorKind::DuplicatedCharacter(who.clone());
+                return Err(DialogError::new(kind, Some(location.clone())).in_file(&module.path));
+            }
+            characters.push(who);
+        }
+    }
+    let portraits: Vec<_> = modules
+        .iter()
+        .flat_map(|module| module.context.decl_portraits.iter())
+        .collect();
+
+    for module in &modules {
+        let namespace = module.namespace.as_deref();
+        // Only own labels and ones from directly imported files can be used
+        let is_visible = |ident: &Identifier| match ident.as_str().split_once('.') {
+            Some((prefix, _)) => {
+                namespace == Some(prefix) || module.imported.iter().any(|item| item == prefix)
+            }
+            None => true,
+        };
+        let context = &module.context;
+        let missing_label = context
+            .expected_labels
+            .iter()
+            .find(|(ident, _)| !is_visible(ident) || !labels.contains(&qualify(ident, namespace)))
+            .map(|(ident, location)| (ErrorKind::UndefinedLabel(ident.clone()), location));
+        let missing_choice = context
+            .expected_choices
+            .iter()
+            .find(|(ident, _)| !is_visible(ident) || !choices.contains(&qualify(ident, namespace)))
+            .map(|(ident, location)| (ErrorKind::UndefinedChoice(ident.clone()), location));
+        let stray_return = context
+            .returns
+            .iter()
+            .find(|(labels, _)| {
+                !labels
+                    .iter()
+                    .any(|label| called.contains(&qualify(label, namespace)))
+            })
+            .map(|(labels, location)| (ErrorKind::ReturnOutsideCall(labels[0].clone()), location));
+        let missing_character = context
+            .expected_characters
+            .iter()
+            .find(|(who, _)| !characters.contains(&who))
+            .map(|(who, location)| (ErrorKind::UndefinedCharacter(who.clone()), location));
+        let missing_portrait = context
+            .expected_portraits
+            .iter()
+            .find(|(pair, _)| !portraits.contains(&pair))
+            .map(|((who, portrait), location)| {
+                (
+                    ErrorKind::UndefinedPortrait(who.clone(), portrait.clone()),
+                    location,
+                )
+            });
+        report_first([
+            missing_label,
+            missing_choice,
+            stray_return,
+            missing_character,
+            missing_portrait,
+        ])
+        .map_err(|err| err.in_file(&module.path))?;
+    }
+
+    // Root file is loaded the last, but its code goes first
+    let mut ast = Vec::new();
+    for module in modules.iter_mut().rev() {
+        if let Some(namespace) = &module.namespace {
+            qualify_nodes(&mut module.ast, namespace);
+        }
+        ast.append(&mut module.ast);
+    }
+    DirectScript::try_from(ast.as_slice())
+}
+
+fn qualify(ident: &Identifier, namespace: Option<&str>) -> Identifier {
+    match namespace {
+        Some(namespace) if !ident.is_qualified() => {
+            format!("{namespace}.{}", ident.as_str()).as_str().into()
+        }
+        _ => ident.clone(),
+    }
+}
+
+fn qualify_nodes(nodes: &mut [AstNode], namespace: &str) {
+    let qualify = |ident: &mut Identifier| *ident = qualify(ident, Some(namespace));
+    // Line ids may already have a dot, but they still belong to this file
+    let prefix = |id: &mut Identifier| *id = format!("{namespace}.{}", id.as_str()).as_str().into();
+    for node in nodes {
+        match node {
+            AstNode::Label(ident) => qualify(ident),
+            AstNode::Choices(ident, options) => {
+                qualify(ident);
+                for (_, _, condition) in options {
+                    qualify_condition(condition, namespace);
+                }
+            }
+            AstNode::LabelBlock(ident, nodes) | AstNode::Once(ident, nodes) => {
+                qualify(ident);
+                qualify_nodes(nodes, namespace);
+            }
+            AstNode::Command(
+                Command::Jump(ident) | Command::Call(ident) | Command::Choice(_, ident, _),
+            ) => qualify(ident),
+            AstNode::Command(
+                Command::Set(_, value) | Command::Add(_, value) | Command::Sub(_, value),
+            ) => qualify_expression(value, namespace),
+            AstNode::Command(Command::Trigger(_, arguments, _)) => {
+                for argument in arguments {
+                    qualify_expression(argument, namespace);
+                }
+            }
+            AstNode::IfBlock(branches, else_nodes) => {
+                for (condition, nodes) in branches {
+                    qualify_expression(condition, namespace);
+                    qualify_nodes(nodes, namespace);
+                }
+                if let Some(nodes) = else_nodes {
+                    qualify_nodes(nodes, namespace);
+                }
+            }
+            AstNode::Dialog(_, _, _, id, _) => prefix(id),
+            AstNode::Menu(_, id, _, options) => {
+                prefix(id);
+                for (_, _, condition, nodes) in options {
+                    qualify_condition(condition, namespace);
+                    qualify_nodes(nodes, namespace);
+                }
+            }
+            AstNode::Random(branches) => {
+                for (_, nodes) in branches {
+                    qualify_nodes(nodes, namespace);
+                }
+            }
+            AstNode::Command(_) | AstNode::Character(..) => {}
+        }
+    }
+}
+
+/// Labels of `visits` and `visited` are qualified the same way as jumps.
+fn qualify_expression(expression: &mut Expression, namespace: &str) {
+    match expression {
+        Expression::Variable(_) => {}
+        Expression::Unary(_, expr) => qualify_expression(expr, namespace),
+        Expression::Binary(lhs, _, rhs) | Expression::Random(lhs, rhs) => {
+            qualify_expression(lhs, namespace);
+            qualify_expression(rhs, namespace);
+        }
+        Expression::Visits(label) => *label = qualify(label, Some(namespace)),
+    }
+}
+
+fn qualify_condition(condition: &mut OptionCondition, namespace: &str) {
+    match condition {
+        OptionCondition::Always => {}
+        OptionCondition::ShownIf(expr) | OptionCondition::EnabledIf(expr) => {
+            qualify_expression(expr, namespace)
+        }
+    }
+}
+
+/// Resolves `.` and `..` without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(result.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                result.pop();
+            }
+            component => result.push(component),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, io, path::Path, rc::Rc};
+
+    use crate::{
+        error::ErrorKind,
+        exec::{DirectExecution, ExecutionStep, extract_po, load_project, load_project_with},
+        interpreter::DirectScript,
+    };
+
+    fn load_from(files: &[(&str, &str)]) -> Result<DirectScript, crate::error::DialogError> {
+        let files: HashMap<_, _> = files.iter().copied().collect();
+        load_project_with("main.drs", |path: &Path| {
+            let path = path.to_str().unwrap();
+            files
+                .get(path)
+                .map(|source| source.to_string())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        })
+    }
+
+    #[test]
+    fn load_project_from_files() {
+        let script = Rc::new(load_project("./res/project/main.drs").unwrap());
+        assert_eq!(script.source(), Some(Path::new("res/project/main.drs")));
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let mut texts = Vec::new();
+        for _ in 0..100 {
+            match exec.step(&mut env) {
+                ExecutionStep::Text(_, text, ..) => texts.push(text.as_str().to_owned()),
+                ExecutionStep::End => break,
+                _ => {}
+            }
+        }
+        assert_eq!(
+            texts,
+            [
+                "Welcome to Faraway Town!",
+                "Hello there!",
+                "Have a nice day.",
+                "Hello there!",
+                "See you!"
+            ]
+        );
+        assert!(DirectExecution::start(&script, "town.greeting").is_some());
+        assert!(DirectExecution::start(&script, "greeting").is_none());
+        // Line ids are prefixed by file name the same way
+        assert!(extract_po(&script).contains("msgctxt \"common.hello."));
+    }
+
+    #[test]
+    fn import_cycle_is_reported() {
+        let error = load_from(&[
+            ("main.drs", "import \"a.drs\"\n"),
+            ("a.drs", "import \"sub/b.drs\"\n"),
+            ("sub/b.drs", "import \"../a.drs\"\n"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::ImportCycle("`a.drs` -> `sub/b.drs` -> `a.drs`".into())
+        );
+        assert_eq!(error.file(), Some(Path::new("sub/b.drs")));
+        assert_eq!(error.location().unwrap().column, 8);
+    }
+
+    #[test]
+    fn shared_import_is_loaded_once() {
+        let script = load_from(&[
+            ("main.drs", "import \"a.drs\"\nimport \"b.drs\"\n"),
+            ("a.drs", "import \"common.drs\"\n"),
+            ("b.drs", "import \"common.drs\"\n"),
+            ("common.drs", "hello:\n    \"Hi!\"\n    end\n"),
+        ]);
+        assert!(script.is_ok());
+    }
+
+    #[test]
+    fn bad_references_are_reported() {
+        // Label of a file that isn't imported directly
+        let error = load_from(&[
+            ("main.drs", "import \"a.drs\"\n\nstart:\n    jump b.hello\n"),
+            ("a.drs", "import \"b.drs\"\n"),
+            ("b.drs", "hello:\n    \"Hi!\"\n    end\n"),
+        ])
+        .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::UndefinedLabel("b.hello".into()));
+        assert_eq!(error.file(), Some(Path::new("main.drs")));
+
+        let error = load_from(&[
+            (
+                "main.drs",
+                "import \"a.drs\"\n\nstart:\n    call a.helper\n    end\n",
+            ),
+            ("a.drs", "helper:\n    call nowhere\n    return\n"),
+        ])
+        .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::UndefinedLabel("nowhere".into()));
+        assert_eq!(error.file(), Some(Path::new("a.drs")));
+
+        let error = load_from(&[("main.drs", "import \"missing.drs\"\n")]).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadImport(_)));
+
+        let error = load_from(&[
+            (
+                "main.drs",
+                "import \"a/common.drs\"\nimport \"b/common.drs\"\n",
+            ),
+            ("a/common.drs", "\n"),
+            ("b/common.drs", "\n"),
+        ])
+        .unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadImport(_)));
+        assert_eq!(error.location().unwrap().line, 2);
+    }
+
+    #[test]
+    fn visits_are_counted_per_file() {
+        let town = "greeting:\n    once\n        \"First time here?\"\n    endonce\n    \
+            if visits(main.greeting) > 1 then\n        \"Again?\"\n    endif\n    return\n";
+        let error = load_from(&[
+            (
+                "main.drs",
+                "import \"town.drs\"\n\nstart:\n    call town.greeting\n    \
+                call town.greeting\n    end\n",
+            ),
+            ("town.drs", town),
+        ])
+        .unwrap_err();
+        // Root file has no namespace, so its labels can't be reached that way
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::UndefinedLabel("main.greeting".into())
+        );
+
+        let town = town.replace("main.greeting", "greeting");
+        let script = Rc::new(
+            load_from(&[
+                (
+                    "main.drs",
+                    "import \"town.drs\"\n\nstart:\n    call town.greeting\n    \
+                    call town.greeting\n    end\n",
+                ),
+                ("town.drs", &town),
+            ])
+            .unwrap(),
+        );
+        let mut env = HashMap::new();
+        let mut exec = DirectExecution::start(&script, "start").unwrap();
+        let mut texts = Vec::new();
+        while let ExecutionStep::Text(_, text, ..) = exec.step(&mut env) {
+            texts.push(text.as_str().to_owned());
+        }
+        assert_eq!(texts, ["First time here?", "Again?"]);
+        assert!(env.contains_key("@visits:town.greeting"));
+    }
+
+    #[test]
+    fn characters_are_shared_by_project() {
+        let cast = "define_character omori that\n    portrait -> neutral\nend_character\n\
+            define_portraits omori neutral sad\n";
+        let files = [
+            (
+                "main.drs",
+                "import \"cast.drs\"\n\nstart:\n    omori[sad] -> \"...\"\n    end\n",
+            ),
+            ("cast.drs", cast),
+        ];
+        assert!(load_from(&files).is_ok());
+
+        let error = load_from(&[
+            (
+                "main.drs",
+                "import \"cast.drs\"\n\nstart:\n    omori[happy] -> \"...\"\n    end\n",
+            ),
+            ("cast.drs", cast),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::UndefinedPortrait("omori".into(), "happy".into())
+        );
+        assert_eq!(error.file(), Some(Path::new("main.drs")));
+
+        let error = load_from(&[
+            ("main.drs", "import \"cast.drs\"\n\ndefine_character omori that\n    name -> \"Omori\"\nend_character\n"),
+            ("cast.drs", cast),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::DuplicatedCharacter("omori".into())
+        );
+        assert_eq!(error.file(), Some(Path::new("main.drs")));
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    error::{DialogError, ErrorKind, Location},
+    grammar::Text,
+    interpreter::DirectScript,
+};
+
+/// Texts of one language, keyed by ids of lines and options.
+#[derive(Debug, Default)]
+pub struct Translation {
+    texts: HashMap<Rc<str>, Text>,
+}
+
+/// Entry of PO file that is being read.
+#[derive(Default)]
+struct Entry {
+    context: Option<String>,
+    text: Option<String>,
+    fuzzy: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Context,
+    Id,
+    Text,
+}
+
+impl Translation {
+    /// Reads gettext PO file, `msgctxt` of every entry is the id of line or option.
+    /// Empty and fuzzy entries are left out, so their lines stay in the source language.
+    pub fn from_po(source: &str) -> Result<Self, DialogError> {
+        let mut translation = Self::default();
+        let mut entry = Entry::default();
+        let mut field = None;
+        let mut offset = 0;
+        for (index, line) in source.split_inclusive('\n').enumerate() {
+            let start = offset;
+            offset += line.len();
+            let line = line.trim_end_matches(['\r', '\n']);
+            let error = |message: String| {
+                let location = Location {
+                    line: index + 1,
+                    column: 1,
+                    range: (start, start + line.len()),
+                    snippet: line.to_owned(),
+                };
+                DialogError::new(ErrorKind::BadTranslation(message), Some(location))
+            };
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            // Comment or new keyword after `msgstr` starts the next entry
+            let starts_entry = trimmed.starts_with('#')
+                || trimmed.starts_with("msgctxt")
+                || trimmed.starts_with("msgid");
+            if starts_entry && entry.text.is_some() {
+                translation.add(std::mem::take(&mut entry)).map_err(error)?;
+                field = None;
+            }
+            if let Some(flags) = trimmed.strip_prefix("#,") {
+                entry.fuzzy |= flags.split(',').any(|flag| flag.trim() == "fuzzy");
+                continue;
+            }
+            if trimmed.starts_with('#') {
+                continue;
+            }
+            let (keyword, literal) = match trimmed.split_once(char::is_whitespace) {
+                _ if trimmed.starts_with('"') => ("", trimmed),
+                Some((keyword, literal)) => (keyword, literal.trim()),
+                None => (trimmed, ""),
+            };
+            field = match keyword {
+                "" => field,
+                "msgctxt" => Some(Field::Context),
+                "msgid" => Some(Field::Id),
+                "msgstr" => Some(Field::Text),
+                "msgid_plural" => return Err(error("plural forms aren't supported".into())),
+                _ if keyword.starts_with("msgstr[") => {
+                    return Err(error("plural forms aren't supported".into()));
+                }
+                _ => return Err(error(format!("unexpected `{keyword}`"))),
+            };
+            let value = unquote(literal).map_err(error)?;
+            let target = match field {
+                Some(Field::Context) => &mut entry.context,
+                // Source text isn't needed, the id is enough
+                Some(Field::Id) => continue,
+                Some(Field::Text) => &mut entry.text,
+                None => return Err(error("string doesn't belong to any keyword".into())),
+            };
+            // Only continuation lines are appended, keywords start the string anew
+            match target {
+                Some(text) if keyword.is_empty() => text.push_str(&value),
+                _ => *target = Some(value),
+            }
+        }
+        let end = source.lines().count();
+        translation.add(entry).map_err(|message| {
+            let location = Location {
+                line: end,
+                column: 1,
+                range: (source.len(), source.len()),
+                snippet: source.lines().next_back().unwrap_or_default().to_owned(),
+            };
+            DialogError::new(ErrorKind::BadTranslation(message), Some(location))
+        })?;
+        Ok(translation)
+    }
+
+    /// Text for the line or option, absent when it isn't translated.
+    pub fn get(&self, id: &str) -> Option<&Text> {
+        self.texts.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+
+    fn add(&mut self, entry: Entry) -> Result<(), String> {
+        // Header has no context, untranslated entries have no text
+        let (Some(id), Some(text)) = (entry.context, entry.text) else {
+            return Ok(());
+        };
+        if text.is_empty() || entry.fuzzy {
+            return Ok(());
+        }
+        if self.texts.contains_key(id.as_str()) {
+            return Err(format!("`{id}` is translated more than once"));
+        }
+        self.texts.insert(id.into(), text.as_str().into());
+        Ok(())
+    }
+}
+
+/// Writes every line and option of the script as gettext PO template,
+/// translators fill `msgstr` of every entry.
+pub fn extract_po(script: &DirectScript) -> String {
+    let mut po =
+        String::from("msgid \"\"\nmsgstr \"\"\n\"Content-Type: text/plain; charset=UTF-8\\n\"\n");
+    for (id, text) in script.sources() {
+        po.push_str(&format!(
+            "\nmsgctxt {}\nmsgid {}\nmsgstr \"\"\n",
+            quote(&id),
+            quote(text.as_str())
+        ));
+    }
+    po
+}
+
+fn quote(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() + 2);
+    result.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            ch => result.push(ch),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn unquote(literal: &str) -> Result<String, String> {
+    let inner = literal
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| format!("expected quoted string, got `{literal}`"))?;
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('"') => result.push('"'),
+                Some('\\') => result.push('\\'),
+                other => {
+                    let other = other.map(String::from).unwrap_or_default();
+                    return Err(format!("invalid escape sequence `\\{other}`"));
+                }
+            },
+            '"' => return Err("`\"` inside of string has to be escaped".into()),
+            ch => result.push(ch),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::read_to_string;
+
+    use super::{Translation, extract_po};
+    use crate::{error::ErrorKind, grammar::parse_to_ast, interpreter::DirectScript};
+
+    #[test]
+    fn po_entries_are_read() {
+        let po = "msgid \"\"\nmsgstr \"\"\n\"Language: ja\\n\"\n\n\
+            # greeting\nmsgctxt \"start.hi\"\nmsgid \"Hi!\"\nmsgstr \"\"\n\"こんにちは\"\n\"！\"\n\n\
+            msgctxt \"start.bye\"\nmsgid \"Bye.\"\nmsgstr \"\"\n\n\
+            #, fuzzy\nmsgctxt \"start.quote\"\nmsgid \"\\\"Hm\\\"\"\nmsgstr \"「ふむ」\"\n\n\
+            msgctxt \"start.new_line\"\nmsgid \"A\\nB\"\nmsgstr \"あ\\\\い\\nう\"";
+        let translation = Translation::from_po(po).unwrap();
+        assert_eq!(translation.len(), 2);
+        assert_eq!(
+            translation.get("start.hi").unwrap().as_str(),
+            "こんにちは！"
+        );
+        // Empty and fuzzy entries are left for the source language
+        assert!(translation.get("start.bye").is_none());
+        assert!(translation.get("start.quote").is_none());
+        assert_eq!(
+            translation.get("start.new_line").unwrap().as_str(),
+            "あ\\い\nう"
+        );
+    }
+
+    #[test]
+    fn bad_po_is_reported() {
+        let error = Translation::from_po("msgctxt \"a\"\nmsgid \"A\"\nmsgstr \"B\n").unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::BadTranslation(_)));
+        assert_eq!(error.location().unwrap().line, 3);
+
+        let po = "msgctxt \"a\"\nmsgid \"A\"\nmsgstr \"B\"\n\nmsgctxt \"a\"\nmsgid \"A\"\nmsgstr \"C\"\n";
+        let error = Translation::from_po(po).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::BadTranslation("`a` is translated more than once".into())
+        );
+        assert_eq!(error.location().unwrap().line, 7);
+
+        let error = Translation::from_po("msgid_plural \"As\"\n").unwrap_err();
+        assert_eq!(error.location().unwrap().line, 1);
+    }
+
+    #[test]
+    fn extracted_texts_are_read_back() {
+        let source = read_to_string("./res/test.drs").unwrap();
+        let ast = parse_to_ast(&source).unwrap();
+        let script = DirectScript::try_from(ast.as_slice()).unwrap();
+        // Template translates everything into itself
+        let po = extract_po(&script).replace("msgstr \"\"\n", "");
+        let po = po.replace("msgid ", "msgstr ");
+        let translation = Translation::from_po(&po).unwrap();
+        assert_eq!(translation.len(), script.sources().count());
+        for (id, text) in script.sources() {
+            assert_eq!(translation.get(&id).unwrap().as_str(), text.as_str());
+        }
+        assert!(translation.get("another_dialog").is_some());
+        assert!(translation.get("yes_no.yes").is_some());
+        assert!(translation.get("test_menu@answer.apple").is_some());
+    }
+}
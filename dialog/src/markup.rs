@@ -0,0 +1,236 @@
+use std::{ops::Range, rc::Rc};
+
+/// Style applied to a part of dialog text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Style {
+    /// `~text~` or `[wavy]text[/wavy]`
+    Wavy,
+    /// `[shake]text[/shake]`
+    Shaking,
+    /// `[color=red]text[/color]`, accepts color names and `#rrggbb`
+    Color(Rc<str>),
+    /// `[b]text[/b]`
+    Bold,
+    /// `[size=24]text[/size]`
+    Size(u32),
+    /// `[speed=0.5]text[/speed]`, multiplier of text animation speed
+    Speed(f32),
+}
+
+/// Styled part of plain text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StyledSpan {
+    pub range: Range<usize>,
+    pub style: Style,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct MarkupError {
+    pub range: Range<usize>,
+    pub message: String,
+}
+
+/// `[pause=1.5]` or `[stop]`: position in plain text and pause in seconds before
+/// the rest of text, `[stop]` waits for the player instead.
+pub(crate) type Pause = (usize, Option<f32>);
+
+/// What tag inside of the text means.
+enum Tag {
+    Style(Style),
+    Pause(Option<f32>),
+}
+
+/// Open tag: its name, where it was opened in plain text and in source.
+struct OpenTag<'a> {
+    name: &'a str,
+    style: Style,
+    start: usize,
+    source: Range<usize>,
+}
+
+/// Strips markup from the text, returning plain text with its styled spans and pauses.
+/// Literal `~` and `[` are written as `~~` and `[[`.
+/// Spans are sorted by start, outer spans come before inner ones.
+pub(crate) fn parse_markup(
+    text: &str,
+) -> Result<(String, Vec<StyledSpan>, Vec<Pause>), MarkupError> {
+    let mut plain = String::with_capacity(text.len());
+    let mut spans = Vec::new();
+    let mut pauses = Vec::new();
+    let mut open: Vec<OpenTag> = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((position, ch)) = chars.next() {
+        match ch {
+            '~' | '[' if chars.next_if(|(_, next)| *next == ch).is_some() => plain.push(ch),
+            '~' => {
+                if open.last().is_some_and(|tag| tag.name == "~") {
+                    let tag = open.pop().unwrap();
+                    spans.push(StyledSpan {
+                        range: tag.start..plain.len(),
+                        style: tag.style,
+                    });
+                } else {
+                    open.push(OpenTag {
+                        name: "~",
+                        style: Style::Wavy,
+                        start: plain.len(),
+                        source: position..position + 1,
+                    });
+                }
+            }
+            '[' => {
+                let Some(length) = text[position..].find(']') else {
+                    return Err(MarkupError {
+                        range: position..text.len(),
+                        message: "unclosed `[`, use `[[` to show it".into(),
+                    });
+                };
+                let source = position..position + length + 1;
+                while chars.next_if(|(next, _)| *next < source.end).is_some() {}
+                let content = &text[position + 1..position + length];
+                if let Some(name) = content.strip_prefix('/') {
+                    let Some(tag) = open.pop().filter(|tag| tag.name == name) else {
+                        return Err(MarkupError {
+                            range: source,
+                            message: format!("`[/{name}]` doesn't close any tag"),
+                        });
+                    };
+                    spans.push(StyledSpan {
+                        range: tag.start..plain.len(),
+                        style: tag.style,
+                    });
+                    continue;
+                }
+                let (name, value) = match content.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (content, None),
+                };
+                let tag = parse_tag(name, value).map_err(|message| MarkupError {
+                    range: source.clone(),
+                    message,
+                })?;
+                match tag {
+                    Tag::Pause(pause) => pauses.push((plain.len(), pause)),
+                    Tag::Style(style) => open.push(OpenTag {
+                        name,
+                        style,
+                        start: plain.len(),
+                        source,
+                    }),
+                }
+            }
+            _ => plain.push(ch),
+        }
+    }
+    if let Some(tag) = open.pop() {
+        return Err(MarkupError {
+            message: format!("`{}` is never closed", &text[tag.source.clone()]),
+            range: tag.source,
+        });
+    }
+    spans.sort_by(|a, b| {
+        let a = (a.range.start, std::cmp::Reverse(a.range.end));
+        let b = (b.range.start, std::cmp::Reverse(b.range.end));
+        a.cmp(&b)
+    });
+    Ok((plain, spans, pauses))
+}
+
+/// Joins parts of a line into one text, the player continues to every next part.
+pub(crate) fn join_parts<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+    parts.into_iter().collect::<Vec<_>>().join("[stop]")
+}
+
+/// Color name or hex code with `#`.
+pub(crate) fn is_valid_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => matches!(hex.len(), 3 | 6 | 8) && hex.chars().all(|ch| ch.is_ascii_hexdigit()),
+        None => !color.is_empty() && color.chars().all(|ch| ch.is_ascii_alphabetic()),
+    }
+}
+
+fn parse_tag(name: &str, value: Option<&str>) -> Result<Tag, String> {
+    let style = match (name, value) {
+        ("wavy", None) => Style::Wavy,
+        ("shake", None) => Style::Shaking,
+        ("b", None) => Style::Bold,
+        ("color", Some(color)) => {
+            if !is_valid_color(color) {
+                return Err(format!("`{color}` is not a color"));
+            }
+            Style::Color(color.into())
+        }
+        ("size", Some(size)) => match size.parse() {
+            Ok(size) if size > 0 => Style::Size(size),
+            _ => return Err(format!("`{size}` is not a valid size")),
+        },
+        ("speed", Some(speed)) => match speed.parse::<f32>() {
+            Ok(speed) if speed.is_finite() && speed > 0.0 => Style::Speed(speed),
+            _ => return Err(format!("`{speed}` is not a valid speed")),
+        },
+        ("pause", Some(pause)) => match pause.parse::<f32>() {
+            Ok(pause) if pause.is_finite() && pause >= 0.0 => return Ok(Tag::Pause(Some(pause))),
+            _ => return Err(format!("`{pause}` is not a valid pause")),
+        },
+        ("stop", None) => return Ok(Tag::Pause(None)),
+        ("wavy" | "shake" | "b" | "stop", Some(_)) => {
+            return Err(format!("`{name}` doesn't take a value"));
+        }
+        ("color" | "size" | "speed" | "pause", None) => {
+            return Err(format!("`{name}` requires a value, like `[{name}=...]`"));
+        }
+        _ => return Err(format!("unknown tag `{name}`")),
+    };
+    Ok(Tag::Style(style))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Style, StyledSpan, parse_markup};
+
+    #[test]
+    fn plain_text_is_untouched() {
+        let (plain, spans, pauses) = parse_markup("Just text, {name}!").unwrap();
+        assert_eq!(plain, "Just text, {name}!");
+        assert!(spans.is_empty());
+        assert!(pauses.is_empty());
+    }
+
+    #[test]
+    fn styles_are_extracted() {
+        let (plain, spans, pauses) =
+            parse_markup("Oh, just ~shut [b]app[/b]...~[pause=0.5] [color=red]Now[/color]~~")
+                .unwrap();
+        assert_eq!(plain, "Oh, just shut app... Now~");
+        assert_eq!(
+            spans,
+            [
+                StyledSpan {
+                    range: 9..20,
+                    style: Style::Wavy
+                },
+                StyledSpan {
+                    range: 14..17,
+                    style: Style::Bold
+                },
+                StyledSpan {
+                    range: 21..24,
+                    style: Style::Color("red".into())
+                },
+            ]
+        );
+        assert_eq!(pauses, [(20, Some(0.5))]);
+    }
+
+    #[test]
+    fn invalid_markup() {
+        assert_eq!(parse_markup("~never closed").unwrap_err().range, 0..1);
+        assert_eq!(parse_markup("[b]x[/shake]").unwrap_err().range, 4..12);
+        assert_eq!(parse_markup("[b]~x[/b]~").unwrap_err().range, 5..9);
+        assert_eq!(parse_markup("a [b").unwrap_err().range, 2..4);
+        assert_eq!(parse_markup("[blink]x[/blink]").unwrap_err().range, 0..7);
+        assert_eq!(parse_markup("[size=big]x[/size]").unwrap_err().range, 0..10);
+        assert_eq!(parse_markup("[color]x[/color]").unwrap_err().range, 0..7);
+        assert_eq!(parse_markup("x[stop=1]").unwrap_err().range, 1..9);
+    }
+}
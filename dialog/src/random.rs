@@ -0,0 +1,76 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Small generator (SplitMix64), the same seed always gives the same numbers.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Current state, [`Rng::new`] with it goes on with the same numbers.
+    pub fn state(&self) -> u64 {
+        self.0
+    }
+
+    /// Seed that differs from run to run.
+    pub fn seed_from_time() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Number in `0..bound`, bound must not be zero.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    /// Int in `min..=max`.
+    pub fn int_in(&mut self, min: i32, max: i32) -> i32 {
+        let (min, max) = (min.min(max), min.max(max));
+        let span = (max as i64 - min as i64 + 1) as u64;
+        (min as i64 + self.below(span) as i64) as i32
+    }
+
+    /// Float in `0.0..1.0`.
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Rng;
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn ranges_are_inclusive() {
+        let mut rng = Rng::new(7);
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            let value = rng.int_in(1, 6);
+            assert!((1..=6).contains(&value));
+            seen[value as usize - 1] = true;
+        }
+        assert!(seen.iter().all(|seen| *seen));
+        assert_eq!(rng.int_in(i32::MIN, i32::MIN), i32::MIN);
+        assert!((0.0..1.0).contains(&rng.unit()));
+    }
+}
@@ -0,0 +1,109 @@
+use std::ops::Range;
+
+/// Piece of dialog text that may reference variables as `{name}`.
+/// Braces are escaped by doubling them: `{{` and `}}`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Piece<'a> {
+    Literal(&'a str),
+    Escaped(char),
+    Variable(&'a str),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct TemplateError {
+    pub range: Range<usize>,
+    pub message: &'static str,
+}
+
+pub(crate) fn has_placeholders(text: &str) -> bool {
+    text.contains(['{', '}'])
+}
+
+/// Splits text into pieces, each with its byte range inside of the text.
+pub(crate) fn parse_template(text: &str) -> Result<Vec<(Range<usize>, Piece<'_>)>, TemplateError> {
+    let mut pieces = Vec::new();
+    let mut literal_start = 0;
+    let mut position = 0;
+    let bytes = text.as_bytes();
+    while position < bytes.len() {
+        let brace = bytes[position];
+        if brace != b'{' && brace != b'}' {
+            position += 1;
+            continue;
+        }
+        if literal_start < position {
+            let range = literal_start..position;
+            pieces.push((range.clone(), Piece::Literal(&text[range])));
+        }
+        if bytes.get(position + 1) == Some(&brace) {
+            pieces.push((position..position + 2, Piece::Escaped(brace as char)));
+            position += 2;
+        } else if brace == b'}' {
+            return Err(TemplateError {
+                range: position..position + 1,
+                message: "unmatched `}`, use `}}` to show it",
+            });
+        } else {
+            let Some(length) = text[position..].find('}') else {
+                return Err(TemplateError {
+                    range: position..text.len(),
+                    message: "unclosed `{`, use `{{` to show it",
+                });
+            };
+            let range = position..position + length + 1;
+            let name = &text[position + 1..position + length];
+            if !is_valid_name(name) {
+                return Err(TemplateError {
+                    range,
+                    message: "expected variable name inside of `{}`",
+                });
+            }
+            pieces.push((range.clone(), Piece::Variable(name)));
+            position = range.end;
+        }
+        literal_start = position;
+    }
+    if literal_start < text.len() {
+        let range = literal_start..text.len();
+        pieces.push((range.clone(), Piece::Literal(&text[range])));
+    }
+    Ok(pieces)
+}
+
+/// Same rules as `name` in grammar.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|ch| ch.is_ascii_alphabetic())
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Piece, parse_template};
+
+    #[test]
+    fn split_into_pieces() {
+        let pieces = parse_template("You have {gold} clams {{!}}").unwrap();
+        let pieces: Vec<_> = pieces.into_iter().map(|(_, piece)| piece).collect();
+        assert_eq!(
+            pieces,
+            [
+                Piece::Literal("You have "),
+                Piece::Variable("gold"),
+                Piece::Literal(" clams "),
+                Piece::Escaped('{'),
+                Piece::Literal("!"),
+                Piece::Escaped('}'),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert_eq!(parse_template("a } b").unwrap_err().range, 2..3);
+        assert_eq!(parse_template("a {b").unwrap_err().range, 2..4);
+        assert_eq!(parse_template("{} b").unwrap_err().range, 0..2);
+        assert_eq!(parse_template("a {1st}").unwrap_err().range, 2..7);
+        assert_eq!(parse_template("a {b c}").unwrap_err().range, 2..7);
+    }
+}
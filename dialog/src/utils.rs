@@ -17,3 +17,10 @@ impl<T: PartialEq> UniquePush<T> for Vec<T> {
         }
     }
 }
+
+/// FNV-1a hash, unlike std hashers it is the same on every platform and version.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
@@ -6,10 +6,18 @@ var name_box: Node2D
 var name_box_text: RichTextLabel
 
 # Text animation
-var stops: Array[int] = []
+var stops: Array[Dictionary] = []
 var current_text_progress = 0
 var current_stop = 0
 var text_anim_running = false
+var pacing: Array[Dictionary] = []
+# Choice is shown and execution waits for `choose`
+var choosing = false
+# Trigger waits for its result, execution goes on after `resume_trigger`
+var awaiting_trigger = false
+# Timed stop or `wait` is going, dialog goes on by itself after it
+var pausing = false
+var pause_left: float = 0.0
 @export_range(1.0, 20.0, 0.1)
 var animation_speed: float = 13.8
 
@@ -18,25 +26,53 @@ func _ready_script() -> void:
 	main_box_text = get_node("../MainBox/Label")
 	name_box = get_node("../NameBox")
 	name_box_text = get_node("../NameBox/Label")
+	main_box_text.bbcode_enabled = true
 
 func _process(delta: float) -> void:
 	process_input()
 	process_text_animation(delta)
 
 func process_text_animation(delta: float):
-	if not text_anim_running or stops.is_empty() or current_text_progress >= stops.back():
+	if pausing:
+		pause_left -= delta
+		if pause_left > 0.0:
+			return
+		pausing = false
+		if current_stop >= stops.size():
+			# Nothing is left to show, so the pause leads to the next step
+			step()
+			return
+	if not text_anim_running or current_stop >= stops.size():
 		return
 	var new_stop = stops[current_stop]
-	current_text_progress += delta * animation_speed
-	if current_text_progress >= new_stop:
-		text_anim_running = false
+	var previous = current_text_progress
+	current_text_progress += delta * animation_speed * speed_at(floor(previous))
+	if current_text_progress >= new_stop["at"]:
+		current_text_progress = new_stop["at"]
 		current_stop += 1
+		if new_stop.has("delay"):
+			pause(new_stop["delay"])
+		else:
+			text_anim_running = false
 	if main_box_text.visible_characters != floor(current_text_progress):
 		main_box_text.visible_characters = floor(current_text_progress)
 
-func _show_text(who: String, text: String, stops: Array[int]) -> void:
-	set_speaker(who)
-	set_text(text, stops[0] if not stops.is_empty() else 0)
+func speed_at(position: int) -> float:
+	for pace in pacing:
+		if pace.has("speed") and pace["start"] <= position and position < pace["end"]:
+			return pace["speed"]
+	return 1.0
+
+func pause(seconds: float) -> void:
+	pausing = true
+	pause_left = seconds
+
+func _show_text(speaker: Dictionary, text: String, stops: Array[Dictionary], pacing: Array[Dictionary], tags: Dictionary) -> void:
+	self.pacing = pacing
+	pausing = false
+	set_speaker(speaker)
+	set_portrait(speaker.get("id", ""), speaker.get("portrait", ""))
+	set_text(text, stops[0]["at"] if not stops.is_empty() else 0)
 	if not stops.is_empty():
 		print("Stops: ", stops)
 		current_text_progress = 0
@@ -45,11 +81,31 @@ func _show_text(who: String, text: String, stops: Array[int]) -> void:
 		self.stops = stops
 	# Todo: safe stops if there is
 
-func _show_choice(store_to: String, choice_names: Array[String], choice_texts: Array[String]) -> void:
-	pass
+func _show_choice(store_to: String, choice_names: Array[String], choice_texts: Array[String], choice_enabled: Array[bool], tags: Dictionary) -> void:
+	choosing = true
 
-func _trigger(what: String) -> void:
-	pass
+func choose(answer) -> void:
+	# Answer may immediately show the next choice
+	choosing = false
+	if not select_choice(answer):
+		choosing = true
+
+func _trigger(what: String, args: Array, awaits_result: bool) -> void:
+	# Nothing handles triggers yet, so every awaited one gets `false`:
+	# `trigger ... -> var` always stores false until real handlers exist
+	if awaits_result:
+		awaiting_trigger = true
+		finish_trigger.call_deferred(false)
+
+func finish_trigger(result) -> void:
+	# Cleared first, result may immediately lead to the next awaited trigger
+	awaiting_trigger = false
+	resume_trigger(result)
+
+func _wait(seconds: float) -> void:
+	stops = []
+	current_stop = 0
+	pause(seconds)
 
 func _end_dialog() -> void:
 	print("You smell like dead flowers")
@@ -67,17 +123,25 @@ func process_input() -> void:
 		step_execution()
 
 func step_execution() -> void:
+	if choosing or pausing or awaiting_trigger:
+		return
 	if current_stop < stops.size():
 		text_anim_running = true
 	else:
 		step()
 
-func set_speaker(name: String) -> void:
-	if name.is_empty():
+func set_speaker(speaker: Dictionary) -> void:
+	if speaker.is_empty():
 		name_box.hide()
 	else:
 		name_box.show()
-		name_box_text.text = name
+		name_box_text.text = speaker["name"]
+		name_box_text.modulate = Color.from_string(speaker.get("color", ""), Color.WHITE)
+		# Todo: play blip sound while text appears
+
+func set_portrait(who: String, portrait: String) -> void:
+	# Todo: show portrait sprite once there are any
+	pass
 
 func set_text(text: String, stop: int = 0) -> void:
 	if stop <= 0:
@@ -1,9 +1,9 @@
-use std::{fs::read_to_string, rc::Rc};
+use std::rc::Rc;
 
 use dialog::exec::Variant as DVariant;
-use dialog::{
-    ast::parse_to_ast,
-    exec::{DirectExecution, DirectScript, Environment},
+use dialog::exec::{
+    load_project, DirectExecution, DirectScript, Environment, Speaker, Stop, Style, StyledSpan,
+    Tags, Translation,
 };
 use godot::{classes::ProjectSettings, prelude::*};
 
@@ -35,12 +35,21 @@ impl INode for DialogManager {
     }
 
     fn ready(&mut self) {
-        let source = ProjectSettings::singleton().globalize_path(&self.script_file);
-        let source = source.to_string();
-        let source = read_to_string(source).unwrap();
-        let ast = parse_to_ast(&source);
-        if let Ok(ast) = ast {
-            self.script = Some(Rc::new(ast.as_slice().into()));
+        // Imports are resolved relative to the root file, so it needs a real path
+        let source = ProjectSettings::singleton()
+            .globalize_path(&self.script_file)
+            .to_string();
+        // Scripts compiled with `compile` binary are loaded without parsing
+        let script = if source.ends_with(".drsc") {
+            std::fs::read(&source)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| DirectScript::from_bytes(&bytes).map_err(|err| err.to_string()))
+        } else {
+            load_project(source).map_err(|err| err.to_string())
+        };
+        match script {
+            Ok(script) => self.script = Some(Rc::new(script)),
+            Err(err) => godot_error!("Failed to load dialog script {}:\n{err}", self.script_file),
         }
         self.ready_script();
     }
@@ -53,6 +62,9 @@ impl DialogManager {
         self.exec = None;
         if let Some(ref script) = self.script {
             self.exec = DirectExecution::start(script, &label);
+            if let Some(ref mut exec) = self.exec {
+                warn_on_missing(exec);
+            }
             let mut game_state = singletons::game_state();
             game_state.bind_mut().change_state(GlobalState::Dialog);
             self.step();
@@ -60,16 +72,98 @@ impl DialogManager {
         self.exec.is_some()
     }
 
+    /// Shows texts from gettext PO `file`, lines it lacks stay in the source language.
+    /// Empty `file` brings back the source language. Can't be done while dialog runs.
+    #[func]
+    fn load_translation(&mut self, file: GString) -> bool {
+        let translation = if file.is_empty() {
+            None
+        } else {
+            let path = ProjectSettings::singleton()
+                .globalize_path(&file)
+                .to_string();
+            let po = match std::fs::read_to_string(&path) {
+                Ok(po) => po,
+                Err(err) => {
+                    godot_error!("Failed to read translation {file}: {err}");
+                    return false;
+                }
+            };
+            match Translation::from_po(&po) {
+                Ok(translation) => Some(translation),
+                Err(err) => {
+                    godot_error!("Failed to load translation {file}:\n{err}");
+                    return false;
+                }
+            }
+        };
+        let Some(script) = self.script.as_mut().and_then(Rc::get_mut) else {
+            godot_warn!("Translation can't be changed without script or while dialog runs!");
+            return false;
+        };
+        if let Err(err) = script.set_translation(translation.as_ref()) {
+            godot_error!("Failed to apply translation {file}:\n{err}");
+            return false;
+        }
+        true
+    }
+
     #[func]
     fn is_running(&mut self) -> bool {
         self.exec.is_some()
     }
 
+    /// State of the running dialog for save games, empty when nothing runs.
+    /// Variables are in `environment`, they have to be saved separately.
+    #[func]
+    fn save_state(&self) -> PackedByteArray {
+        self.exec
+            .as_ref()
+            .map_or_else(PackedByteArray::new, |exec| {
+                PackedByteArray::from(exec.snapshot().as_slice())
+            })
+    }
+
+    /// Continues dialog saved by `save_state`, the shown line is shown again on the next step,
+    /// fails if the script has changed since then.
+    #[func]
+    fn restore_state(&mut self, state: PackedByteArray) -> bool {
+        let Some(ref script) = self.script else {
+            godot_warn!("Dialog can't be restored without script!");
+            return false;
+        };
+        let mut exec = match DirectExecution::restore(script, state.as_slice()) {
+            Ok(exec) => exec,
+            Err(err) => {
+                godot_error!("Failed to restore dialog:\n{err}");
+                return false;
+            }
+        };
+        warn_on_missing(&mut exec);
+        self.exec = Some(exec);
+        let mut game_state = singletons::game_state();
+        game_state.bind_mut().change_state(GlobalState::Dialog);
+        self.step();
+        true
+    }
+
     #[func(virtual)]
     fn ready_script(&mut self) {}
 
+    /// Speaker has `id` and `name`, and optionally `color`, `portrait` and `blip`,
+    /// it is empty when nobody speaks.
+    /// Stops have position `at` in characters, timed ones also have `delay` in seconds.
+    /// Tags of the line map names to values, tags without value are `true`.
     #[func(virtual)]
-    fn show_text(&mut self, who: String, text: String, stops: Vec<u32>) {}
+    fn show_text(
+        &mut self,
+        speaker: Dictionary,
+        text: String,
+        stops: Vec<Dictionary>,
+        pacing: Vec<Dictionary>,
+        tags: Dictionary,
+    ) {
+    }
 
     #[func(virtual)]
     fn show_choice(
@@ -77,15 +171,70 @@ impl DialogManager {
         store_to: String,
         choice_names: Vec<GString>,
         choice_texts: Vec<GString>,
+        choice_enabled: Vec<bool>,
+        tags: Dictionary,
     ) {
     }
 
+    /// When `awaits_result` is set, dialog waits for `resume_trigger`.
+    #[func(virtual)]
+    fn trigger(&mut self, what: String, args: VariantArray, awaits_result: bool) {}
+
+    /// Dialog has to go on with `step` by itself after `seconds`.
     #[func(virtual)]
-    fn trigger(&mut self, what: String) {}
+    fn wait(&mut self, seconds: f32) {}
 
     #[func(virtual)]
     fn end_dialog(&mut self) {}
 
+    /// Picks option of the shown choice by its index or name, then goes on.
+    #[func]
+    fn select_choice(&mut self, answer: Variant) -> bool {
+        let Some(ref mut exec) = self.exec else {
+            godot_warn!("Trying to answer absent execution!");
+            return false;
+        };
+        let env = &mut DictionaryEnv(&mut self.environment);
+        let result = match answer.get_type() {
+            VariantType::INT => {
+                let index = usize::try_from(answer.to::<i64>()).unwrap_or(usize::MAX);
+                exec.answer(env, index)
+            }
+            VariantType::STRING | VariantType::STRING_NAME => {
+                exec.answer(env, answer.to::<String>().as_str())
+            }
+            _ => {
+                godot_warn!("Choice can be selected only by index or name, got {answer}");
+                return false;
+            }
+        };
+        if let Err(err) = result {
+            godot_warn!("Can't select {answer}: {err}");
+            return false;
+        }
+        self.step();
+        true
+    }
+
+    /// Stores result of the awaited trigger, then goes on.
+    #[func]
+    fn resume_trigger(&mut self, result: Variant) -> bool {
+        let Some(ref mut exec) = self.exec else {
+            godot_warn!("Trying to resume absent execution!");
+            return false;
+        };
+        let Some(value) = from_godot_variant(&result) else {
+            godot_warn!("Trigger result can't be stored, got {result}");
+            return false;
+        };
+        if let Err(err) = exec.resume(&mut DictionaryEnv(&mut self.environment), value) {
+            godot_warn!("Can't resume with {result}: {err}");
+            return false;
+        }
+        self.step();
+        true
+    }
+
     #[func]
     fn step(&mut self) {
         let step = if let Some(ref mut exec) = self.exec {
@@ -95,54 +244,180 @@ impl DialogManager {
             return;
         };
         match step {
-            dialog::exec::ExecutionStep::Text(who, text, items) => {
-                let stops = items.into_iter().map(|v| v as u32).collect();
-                let who = who.as_ref().map_or("", |who| who.as_str()).to_string();
-                self.show_text(who, text.as_str().to_string(), stops);
+            dialog::exec::ExecutionStep::Text(who, text, stops, styles, tags) => {
+                let stops = to_stops(&stops);
+                let speaker = who.as_ref().map_or_else(Dictionary::new, to_speaker);
+                let pacing = to_pacing(text.as_str(), &styles);
+                let text = to_bbcode(text.as_str(), &styles);
+                self.show_text(speaker, text, stops, pacing, to_tags(&tags));
             }
-            dialog::exec::ExecutionStep::Choice(identifier, items) => {
-                let (names, texts): (Vec<_>, Vec<_>) = items
-                    .iter()
-                    .map(|(name, text)| (name.as_str().to_godot(), text.as_str().to_godot()))
-                    .unzip();
-                self.show_choice(identifier.as_str().to_string(), names, texts);
+            dialog::exec::ExecutionStep::Choice(identifier, items, tags) => {
+                let mut names = Vec::with_capacity(items.len());
+                let mut texts = Vec::with_capacity(items.len());
+                let mut enabled = Vec::with_capacity(items.len());
+                for (name, text, is_enabled) in items {
+                    names.push(name.as_str().to_godot());
+                    texts.push(text.as_str().to_godot());
+                    enabled.push(is_enabled);
+                }
+                let store_to = identifier.as_str().to_string();
+                self.show_choice(store_to, names, texts, enabled, to_tags(&tags));
             }
-            dialog::exec::ExecutionStep::Trigger(ident) => {
-                self.trigger(ident.as_str().to_string());
+            dialog::exec::ExecutionStep::Trigger(ident, args, store_to) => {
+                let args = args.into_iter().map(to_godot_variant).collect();
+                self.trigger(ident.as_str().to_string(), args, store_to.is_some());
             }
+            dialog::exec::ExecutionStep::Wait(seconds) => self.wait(seconds),
             dialog::exec::ExecutionStep::End => {
                 self.exec = None;
                 self.end_dialog();
             }
+            dialog::exec::ExecutionStep::Error(err) => {
+                godot_error!("Dialog stopped: {err}");
+                self.exec = None;
+                self.end_dialog();
+            }
+        }
+    }
+}
+
+/// Warns about variables that texts use, but nobody has set.
+fn warn_on_missing(exec: &mut DirectExecution) {
+    exec.set_missing_formatter(|name| {
+        godot_warn!("Dialog text uses absent variable `{name}`");
+        format!("{{{name}}}")
+    });
+}
+
+/// Converts visual styles into BBCode of `RichTextLabel`.
+fn to_bbcode(text: &str, styles: &[StyledSpan]) -> String {
+    let mut tags = Vec::new();
+    for (index, span) in styles.iter().enumerate() {
+        let (open, close) = match &span.style {
+            Style::Wavy => ("[wave]".to_string(), "[/wave]"),
+            Style::Shaking => ("[shake]".to_string(), "[/shake]"),
+            Style::Color(color) => (format!("[color={color}]"), "[/color]"),
+            Style::Bold => ("[b]".to_string(), "[/b]"),
+            Style::Size(size) => (format!("[font_size={size}]"), "[/font_size]"),
+            Style::Speed(_) => continue,
+        };
+        if span.range.is_empty() {
+            continue;
+        }
+        // At the same position tags are closed first, inner ones before outer
+        tags.push((span.range.start, 1, index as isize, open));
+        tags.push((span.range.end, 0, -(index as isize), close.to_string()));
+    }
+    tags.sort_by_key(|(position, order, index, _)| (*position, *order, *index));
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for (position, _, _, tag) in tags {
+        result.push_str(&text[last..position].replace('[', "[lb]"));
+        result.push_str(&tag);
+        last = position;
+    }
+    result.push_str(&text[last..].replace('[', "[lb]"));
+    result
+}
+
+/// Speaker in the form `show_text` expects it.
+fn to_speaker(speaker: &Speaker) -> Dictionary {
+    let character = &speaker.character;
+    let mut dict = dict! {
+        "id": speaker.id.as_str(),
+        "name": character.name.as_str(),
+    };
+    if let Some(color) = &character.color {
+        dict.set("color", color.as_str());
+    }
+    if let Some(portrait) = &speaker.portrait {
+        dict.set("portrait", portrait.as_str());
+    }
+    if let Some(blip) = &character.blip {
+        dict.set("blip", blip.as_str());
+    }
+    dict
+}
+
+/// Stops in the form `show_text` expects them.
+fn to_stops(stops: &[Stop]) -> Vec<Dictionary> {
+    stops
+        .iter()
+        .map(|stop| {
+            let mut dict = dict! { "at": stop.position as u32 };
+            if let Some(delay) = stop.delay {
+                dict.set("delay", delay);
+            }
+            dict
+        })
+        .collect()
+}
+
+/// Tags in the form `show_text` and `show_choice` expect them.
+fn to_tags(tags: &Tags) -> Dictionary {
+    let mut dict = Dictionary::new();
+    for (name, value) in tags.iter() {
+        match value {
+            Some(value) => dict.set(name.as_str(), value.as_str()),
+            None => dict.set(name.as_str(), true),
         }
     }
+    dict
+}
+
+/// Collects styles that change text animation, they have no BBCode.
+/// Positions are in characters, same as stops.
+fn to_pacing(text: &str, styles: &[StyledSpan]) -> Vec<Dictionary> {
+    let to_chars = |byte: usize| text[..byte].chars().count() as u32;
+    styles
+        .iter()
+        .filter_map(|span| {
+            let (start, end) = (to_chars(span.range.start), to_chars(span.range.end));
+            match span.style {
+                Style::Speed(speed) => Some(dict! { "start": start, "end": end, "speed": speed }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn to_godot_variant(value: DVariant) -> Variant {
+    match value {
+        DVariant::String(string) => GString::from(&string as &str).to_variant(),
+        DVariant::Int(value) => value.to_variant(),
+        DVariant::Float(value) => value.to_variant(),
+        DVariant::Boolean(value) => value.to_variant(),
+    }
+}
+
+fn from_godot_variant(value: &Variant) -> Option<DVariant> {
+    match value.get_type() {
+        VariantType::BOOL => Some(DVariant::Boolean(value.booleanize())),
+        VariantType::INT => {
+            // Godot ints are 64-bit, those that don't fit are kept as floats
+            let value: i64 = value.to();
+            Some(i32::try_from(value).map_or(DVariant::Float(value as f64), DVariant::Int))
+        }
+        VariantType::FLOAT => Some(DVariant::Float(value.to())),
+        VariantType::STRING | VariantType::STRING_NAME => {
+            Some(DVariant::String(value.to::<String>().into()))
+        }
+        _ => None,
+    }
 }
 
 struct DictionaryEnv<'a>(&'a mut Dictionary);
 
 impl Environment for DictionaryEnv<'_> {
     fn get(&self, name: &str) -> Option<DVariant> {
-        let value = self.0.get(name)?;
-        match value.get_type() {
-            VariantType::BOOL => Some(DVariant::Boolean(value.booleanize())),
-            VariantType::INT => Some(DVariant::Int(value.to())),
-            VariantType::FLOAT => todo!("Not yet!"),
-            VariantType::STRING => Some(DVariant::String(value.to::<String>().into())),
-            _ => {
-                godot_warn!("Got unexpected type from dictionary!");
-                None
-            }
+        let value = from_godot_variant(&self.0.get(name)?);
+        if value.is_none() {
+            godot_warn!("Got unexpected type from dictionary!");
         }
+        value
     }
 
     fn set(&mut self, name: &str, value: DVariant) {
-        self.0.set(
-            name,
-            match value {
-                DVariant::String(string) => GString::from(&string as &str).to_variant(),
-                DVariant::Int(value) => value.to_variant(),
-                DVariant::Boolean(value) => value.to_variant(),
-            },
-        );
+        self.0.set(name, to_godot_variant(value));
     }
 }